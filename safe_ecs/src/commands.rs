@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use crate::{Component, Entity, World};
+use crate::{Bundle, Component, Entity, World};
 
 pub trait Command: 'static {
     fn apply(self: Box<Self>, world: &mut World);
@@ -19,16 +19,40 @@ impl<T: Component> Command for InsertCmd<T> {
     }
 }
 
+struct SpawnWithCmd<B: Bundle>(Entity, B);
+impl<B: Bundle> Command for SpawnWithCmd<B> {
+    fn apply(self: Box<Self>, world: &mut World) {
+        self.1.insert_into(world, self.0);
+    }
+}
+
+struct AddIfCmd<F: FnOnce(&World) -> bool + 'static, C: Command>(F, C);
+impl<F: FnOnce(&World) -> bool + 'static, C: Command> Command for AddIfCmd<F, C> {
+    fn apply(self: Box<Self>, world: &mut World) {
+        let AddIfCmd(cond, cmd) = *self;
+        if cond(world) {
+            Box::new(cmd).apply(world);
+        }
+    }
+}
+
 pub struct CommandBuffer(Vec<Box<dyn Command>>);
 impl CommandBuffer {
     pub fn new() -> Self {
         Self(vec![])
     }
 
+    /// Number of commands waiting to be applied
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn apply(&mut self, world: &mut World) {
-        world
-            .entities
-            .fix_reserved_entities(|reserved| world.archetypes[0].entities.push(reserved));
+        world.flush_reserved();
         for cmd in self.0.drain(..) {
             cmd.apply(world);
         }
@@ -54,10 +78,34 @@ impl<'a> Commands<'a> {
         self
     }
 
+    pub fn add(&mut self, cmd: impl Command) -> &mut Self {
+        self.0 .0.push(Box::new(cmd));
+        self
+    }
+
+    /// Applies `cmd` only if `cond` returns true, `cond` is called when the command
+    /// buffer is applied so it sees the effects of commands added before this one.
+    pub fn add_if(
+        &mut self,
+        cond: impl FnOnce(&World) -> bool + 'static,
+        cmd: impl Command,
+    ) -> &mut Self {
+        self.0 .0.push(Box::new(AddIfCmd(cond, cmd)));
+        self
+    }
+
     pub fn spawn(&mut self) -> CommandsWithEntity<'_, 'a> {
         let e = self.1.entities.reserve_entity();
         CommandsWithEntity(self, e)
     }
+
+    /// Spawns an entity with all of the components in `bundle`, they are inserted
+    /// by a single command so the entity is never observed with only some of them.
+    pub fn spawn_with<B: Bundle>(&mut self, bundle: B) -> CommandsWithEntity<'_, 'a> {
+        let e = self.1.entities.reserve_entity();
+        self.0 .0.push(Box::new(SpawnWithCmd(e, bundle)));
+        CommandsWithEntity(self, e)
+    }
 }
 
 impl CommandsWithEntity<'_, '_> {
@@ -115,4 +163,57 @@ mod tests {
         assert_eq!(iter.next(), Some((e1, &12)));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn spawn_with() {
+        let mut world = World::new();
+        let e1 = world.access_scope(|mut cmds: Commands| cmds.spawn_with((10_u32, 12_u64)).id());
+
+        let mut q = world.query::<(Entity, &u32, &u64)>().unwrap();
+        let mut iter = q.iter_mut();
+        assert_eq!(iter.next(), Some((e1, &10, &12)));
+        assert_eq!(iter.next(), None);
+    }
+
+    struct InsertU64(Entity, u64);
+    impl Command for InsertU64 {
+        fn apply(self: Box<Self>, world: &mut World) {
+            world.insert_component(self.0, self.1);
+        }
+    }
+
+    #[test]
+    fn add_if() {
+        let mut world = World::new();
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        world.access_scope(|mut cmds: Commands| {
+            cmds.insert_component(e1, 10_u32);
+            for e in [e1, e2] {
+                cmds.add_if(
+                    move |world| world.has_component::<u32>(e) == Some(true),
+                    InsertU64(e, 12),
+                );
+            }
+        });
+        assert_eq!(*world.get_component::<u64>(e1).unwrap(), 12);
+        assert_eq!(world.has_component::<u64>(e2), Some(false));
+    }
+
+    #[test]
+    fn buffer_len() {
+        let mut world = World::new();
+        let e1 = world.spawn().id();
+        let mut buffer = CommandBuffer::new();
+        assert!(buffer.is_empty());
+        Commands(&mut buffer, &world)
+            .insert_component(e1, 10_u32)
+            .add(InsertU64(e1, 12));
+        assert_eq!(buffer.len(), 2);
+        assert!(!buffer.is_empty());
+
+        buffer.apply(&mut world);
+        assert!(buffer.is_empty());
+        assert_eq!(*world.get_component::<u64>(e1).unwrap(), 12);
+    }
 }
@@ -19,15 +19,28 @@ fn index_range_of_element(size: usize, align: usize, idx: usize) -> std::ops::Ra
 }
 
 pub trait ErasedBytesVec {
+    /// Panics if `idx` is not less than `num_elements`
     fn get_element_ptr(&self, idx: usize) -> LtPtr<'_>;
+    /// `idx` may be past the end if there is capacity for it so that the space for an
+    /// element can be written to before calling `incr_len`.
     fn get_element_ptr_mut(&mut self, idx: usize) -> LtPtrMut<'_>;
     fn realloc_if_full(&mut self);
     fn empty_of_same_layout(&self) -> Box<dyn ErasedBytesVec>;
     fn swap_remove_move_to(&mut self, other: &mut dyn ErasedBytesVec, idx: usize);
     fn swap_remove(&mut self, idx: usize) -> Option<LtPtrOwn<'_>>;
+    /// Like `swap_remove` but returns a copy of the removed element's bytes
+    fn swap_remove_to_bytes(&mut self, idx: usize) -> Vec<u8>;
+    fn element_to_bytes(&self, idx: usize) -> Vec<u8>;
+    /// Overwrites the element at `idx` with `bytes`, `idx` may be one past the end
+    /// if there is capacity for it, i.e. after calling `realloc_if_full`.
+    fn copy_from_bytes(&mut self, idx: usize, bytes: &[u8]);
+    fn apply_permutation(&mut self, permutation: &[usize]);
     fn copy_to_insert_over_space(&mut self, idx: usize) -> (LtPtrOwn<'_>, LtPtrWriteOnly<'_>);
     fn num_elements(&self) -> usize;
     fn incr_len(&mut self);
+    /// Shortens the vec to `new_len` elements, does nothing if it is already shorter.
+    /// Capacity is unaffected.
+    fn truncate(&mut self, new_len: usize);
 
     fn erased_as_any(&self) -> &dyn Any;
     fn erased_as_any_mut(&mut self) -> &mut dyn Any;
@@ -40,6 +53,7 @@ where
     (): AlignTo<A>,
 {
     fn get_element_ptr(&self, idx: usize) -> LtPtr<'_> {
+        self.assert_in_bounds(idx);
         let idx = index_range_of_element(self.size, A, idx);
         let ptr = &self.buf[idx] as *const [_] as *const MaybeUninit<u8>;
         let ptr = std::ptr::slice_from_raw_parts(ptr, self.size);
@@ -47,6 +61,7 @@ where
     }
 
     fn get_element_ptr_mut(&mut self, idx: usize) -> LtPtrMut<'_> {
+        self.assert_in_capacity(idx);
         let idx = index_range_of_element(self.size, A, idx);
         let ptr = &mut self.buf[idx] as *mut [_] as *mut MaybeUninit<u8>;
         let ptr = std::ptr::slice_from_raw_parts_mut(ptr, self.size);
@@ -58,7 +73,7 @@ where
             return;
         }
 
-        if self.len_elements == (self.buf.len() / self.size) {
+        if self.len_elements == self.capacity() {
             match self.buf.len() {
                 0 => self.buf.resize_with(self.size, Default::default),
                 n => self.buf.resize_with(n * 2, Default::default),
@@ -106,7 +121,7 @@ where
         let dst = index_range_of_element(self.size, A, idx);
 
         let (dst_slice, src_slice) = self.buf.as_mut_slice().split_at_mut(src.start);
-        let src_slice = &mut src_slice[0..self.size];
+        let src_slice = &mut src_slice[0..src.len()];
         let dst_slice = &mut dst_slice[dst];
 
         for (src, dst) in src_slice.into_iter().zip(dst_slice.into_iter()) {
@@ -118,6 +133,39 @@ where
         Some(LtPtrOwn(Default::default(), ptr.1 as *const _))
     }
 
+    fn swap_remove_to_bytes(&mut self, idx: usize) -> Vec<u8> {
+        self.swap_remove(idx);
+        // the removed element is swapped to just past the end
+        self.element_to_bytes(self.len_elements)
+    }
+
+    fn element_to_bytes(&self, idx: usize) -> Vec<u8> {
+        let element = index_range_of_element(self.size, A, idx);
+        self.buf[element]
+            .iter()
+            .flat_map(|chunk| chunk.as_ref())
+            .copied()
+            .collect()
+    }
+
+    fn copy_from_bytes(&mut self, idx: usize, bytes: &[u8]) {
+        assert_eq!(bytes.len(), self.size);
+        let dst = index_range_of_element(self.size, A, idx);
+        for (dst, src) in self.buf[dst].iter_mut().zip(bytes.chunks(A)) {
+            dst.as_mut().copy_from_slice(src);
+        }
+    }
+
+    fn apply_permutation(&mut self, permutation: &[usize]) {
+        let mut buf = vec![<_>::default(); self.buf.len()];
+        for (dst_idx, &src_idx) in permutation.iter().enumerate() {
+            let src = index_range_of_element(self.size, A, src_idx);
+            let dst = index_range_of_element(self.size, A, dst_idx);
+            buf[dst].copy_from_slice(&self.buf[src]);
+        }
+        self.buf = buf;
+    }
+
     fn copy_to_insert_over_space(&mut self, idx: usize) -> (LtPtrOwn<'_>, LtPtrWriteOnly<'_>) {
         let src_idx = index_range_of_element(self.size, A, idx);
         let src = &mut self.buf[src_idx];
@@ -139,12 +187,17 @@ where
         self.len_elements
     }
 
+    fn truncate(&mut self, new_len: usize) {
+        self.len_elements = usize::min(self.len_elements, new_len);
+    }
+
     fn incr_len(&mut self) {
         if self.size == 0 {
             self.len_elements += 1;
+            return;
         }
 
-        assert!(self.len_elements < (self.buf.len() / self.size));
+        assert!(self.len_elements < self.capacity());
         self.len_elements += 1;
     }
 
@@ -196,17 +249,50 @@ where
 {
     fn new(size: usize) -> Box<Self> {
         assert!(size == 0 || size % A == 0);
+        let mut inserted_over_space = Vec::new();
+        // not `vec![..; n]` as that creates an element even when `n == 0` which overflows
+        // the stack for very large alignments
+        inserted_over_space.resize_with(size / A, Default::default);
         Box::new(Self {
-            inserted_over_space: vec![<_>::default(); size],
+            inserted_over_space,
             buf: Vec::new(),
             len_elements: 0,
             size,
         })
     }
+
+    fn assert_in_bounds(&self, idx: usize) {
+        assert!(
+            idx < self.len_elements,
+            "element index {} out of bounds, column has {} elements",
+            idx,
+            self.len_elements,
+        );
+    }
+
+    /// Elements past `len_elements` are allowed so that the space for an element can be
+    /// written to before calling `incr_len`.
+    fn assert_in_capacity(&self, idx: usize) {
+        if self.size != 0 {
+            assert!(
+                idx < self.capacity(),
+                "element index {} out of bounds, column has {} elements and capacity for {}",
+                idx,
+                self.len_elements,
+                self.capacity()
+            );
+        }
+    }
+
+    /// Number of elements that fit in `buf`, `buf` is measured in `A` byte chunks
+    /// not elements or bytes. Should not be called for zero sized elements.
+    fn capacity(&self) -> usize {
+        self.buf.len() * A / self.size
+    }
 }
 
 trait AlignTo<const A: usize> {
-    type Aligned: Copy + Default;
+    type Aligned: Copy + Default + AsRef<[u8]> + AsMut<[u8]>;
 }
 
 macro_rules! aligned_bytes_type_defs {
@@ -220,6 +306,16 @@ macro_rules! aligned_bytes_type_defs {
                     Self([0; $num])
                 }
             }
+            impl AsRef<[u8]> for $name {
+                fn as_ref(&self) -> &[u8] {
+                    &self.0
+                }
+            }
+            impl AsMut<[u8]> for $name {
+                fn as_mut(&mut self) -> &mut [u8] {
+                    &mut self.0
+                }
+            }
 
             impl AlignTo<$num> for () { type Aligned = $name; }
         )*
@@ -229,12 +325,19 @@ macro_rules! aligned_bytes_type_defs {
                 $(
                     $num => AlignedBytesVec::<$num>::new(layout.size()),
                 )*
-                _ => panic!("Invalid alignment, only powers of two up to 2^29 supported"),
+                // `repr(align)` is capped at 2^29 so larger alignments can't be supported
+                align => panic!(
+                    "Invalid alignment {}, only powers of two up to 2^29 ({}) are supported",
+                    align,
+                    MAX_ALIGN
+                ),
             }
         }
     };
 }
 
+const MAX_ALIGN: usize = 536870912;
+
 aligned_bytes_type_defs! {
     AlignedBytes1 1
     AlignedBytes2 2
@@ -267,3 +370,147 @@ aligned_bytes_type_defs! {
     AlignedBytes268435456 268435456
     AlignedBytes536870912 536870912
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "element index 1 out of bounds, column has 1 elements")]
+    fn element_out_of_bounds() {
+        let mut vec = make_aligned_vec(Layout::new::<u32>());
+        push(&mut *vec, 10_u32);
+        // in capacity but not yet written to
+        vec.get_element_ptr(1);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "element index 4 out of bounds, column has 1 elements and capacity for 4"
+    )]
+    fn element_out_of_capacity() {
+        let mut vec = make_aligned_vec(Layout::new::<u32>());
+        push(&mut *vec, 10_u32);
+        vec.get_element_ptr_mut(3);
+        vec.get_element_ptr_mut(4);
+    }
+
+    #[test]
+    fn max_alignment() {
+        let vec = make_aligned_vec(Layout::from_size_align(0, MAX_ALIGN).unwrap());
+        assert_eq!(vec.num_elements(), 0);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Invalid alignment 1073741824, only powers of two up to 2^29 (536870912)"
+    )]
+    fn above_max_alignment() {
+        make_aligned_vec(Layout::from_size_align(0, MAX_ALIGN * 2).unwrap());
+    }
+
+    fn push<T: Copy>(vec: &mut dyn ErasedBytesVec, value: T) {
+        vec.realloc_if_full();
+        let len = vec.num_elements();
+        let ptr = vec.get_element_ptr_mut(len);
+        assert_eq!(ptr.1.len(), std::mem::size_of::<T>());
+        unsafe { *(ptr.1 as *mut T) = value };
+        vec.incr_len();
+    }
+
+    fn get<T: Copy>(vec: &dyn ErasedBytesVec, idx: usize) -> T {
+        unsafe { *(vec.get_element_ptr(idx).1 as *const T) }
+    }
+
+    fn buf_len<const A: usize>(vec: &dyn ErasedBytesVec) -> usize
+    where
+        (): AlignTo<A>,
+    {
+        let vec: &AlignedBytesVec<A> = vec.erased_as_any().downcast_ref().unwrap();
+        vec.buf.len()
+    }
+
+    #[test]
+    fn truncate() {
+        let mut vec = make_aligned_vec(Layout::new::<u32>());
+        for n in 0..6_u32 {
+            push(&mut *vec, n);
+        }
+        vec.truncate(10);
+        assert_eq!(vec.num_elements(), 6);
+        vec.truncate(2);
+        assert_eq!(vec.num_elements(), 2);
+        assert_eq!(buf_len::<4>(&*vec), 8);
+        push(&mut *vec, 10_u32);
+        assert_eq!(get::<u32>(&*vec, 1), 1);
+        assert_eq!(get::<u32>(&*vec, 2), 10);
+        vec.truncate(0);
+        assert_eq!(vec.num_elements(), 0);
+    }
+
+    #[test]
+    fn fill_to_capacity() {
+        let mut vec = make_aligned_vec(Layout::new::<u32>());
+        for n in 0..4_u32 {
+            push(&mut *vec, n);
+        }
+        // exactly full, pushing the next element must be what grows the buffer
+        assert_eq!(buf_len::<4>(&*vec), 4);
+        push(&mut *vec, 4_u32);
+        assert_eq!(buf_len::<4>(&*vec), 8);
+        for n in 5..8_u32 {
+            push(&mut *vec, n);
+        }
+        assert_eq!(buf_len::<4>(&*vec), 8);
+        for n in 0..8_u32 {
+            assert_eq!(get::<u32>(&*vec, n as usize), n);
+        }
+    }
+
+    #[test]
+    fn fill_to_capacity_oversized() {
+        let mut vec = make_aligned_vec(Layout::new::<[u16; 3]>());
+        for n in 0..2_u16 {
+            push(&mut *vec, [n; 3]);
+        }
+        assert_eq!(buf_len::<2>(&*vec), 6);
+        push(&mut *vec, [2_u16; 3]);
+        assert_eq!(buf_len::<2>(&*vec), 12);
+        push(&mut *vec, [3_u16; 3]);
+        assert_eq!(buf_len::<2>(&*vec), 12);
+        for n in 0..4_u16 {
+            assert_eq!(get::<[u16; 3]>(&*vec, n as usize), [n; 3]);
+        }
+    }
+
+    #[test]
+    fn move_to_full_vec() {
+        let mut src = make_aligned_vec(Layout::new::<u64>());
+        let mut dst = src.empty_of_same_layout();
+        for n in 0..16_u64 {
+            push(&mut *src, n);
+        }
+        for n in 100..108_u64 {
+            push(&mut *dst, n);
+        }
+        assert_eq!(buf_len::<8>(&*dst), 8);
+        src.swap_remove_move_to(&mut *dst, 0);
+        assert_eq!(buf_len::<8>(&*dst), 16);
+        assert_eq!(dst.num_elements(), 9);
+        assert_eq!(get::<u64>(&*dst, 8), 0);
+        assert_eq!(src.num_elements(), 15);
+        assert_eq!(get::<u64>(&*src, 0), 15);
+    }
+
+    #[test]
+    fn zero_sized() {
+        let mut vec = make_aligned_vec(Layout::new::<()>());
+        for _ in 0..5 {
+            push(&mut *vec, ());
+        }
+        assert_eq!(vec.num_elements(), 5);
+        assert_eq!(vec.iter().count(), 5);
+        vec.swap_remove(0);
+        assert_eq!(vec.num_elements(), 4);
+    }
+}
@@ -1,7 +1,49 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicIsize, Ordering};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
-pub struct Entity(pub(crate) usize);
+/// An index into the world's entity slots along with the generation of the slot when this
+/// entity was spawned. Despawning an entity bumps its slot's generation so that stale
+/// handles to it are not alive even if the slot is later reused.
+#[derive(Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct Entity {
+    index: u32,
+    generation: u32,
+}
+
+impl Entity {
+    pub(crate) fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    /// The index of the slot this entity occupies
+    pub fn index(self) -> usize {
+        self.index as usize
+    }
+
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+
+    /// Whether `self` and `other` occupy the same slot, ignoring generations
+    pub fn same_index(self, other: Entity) -> bool {
+        self.index() == other.index()
+    }
+}
+
+impl std::fmt::Debug for Entity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Entity")
+            .field("index", &self.index)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+/// Formats as `e{index}v{generation}`, e.g. `e12v0`
+impl std::fmt::Display for Entity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "e{}v{}", self.index, self.generation)
+    }
+}
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub(crate) struct EntityMeta {
@@ -10,15 +52,29 @@ pub(crate) struct EntityMeta {
 
 #[derive(Debug)]
 pub(crate) struct Entities {
-    len: AtomicUsize,
-    meta: Vec<Option<EntityMeta>>,
+    pub(crate) meta: Vec<Option<EntityMeta>>,
+    /// The current generation of each slot in `meta`
+    generations: Vec<u32>,
+    /// Indices of despawned slots that can be reused
+    free_list: Vec<u32>,
+    /// Entities are reserved by decrementing this. While positive the reserved entity is
+    /// `free_list[free_cursor - 1]`, once it goes negative `-free_cursor` new slots past the
+    /// end of `meta` have been reserved. Reserved entities are made alive by
+    /// `fix_reserved_entities` which resets this to `free_list.len()`.
+    free_cursor: AtomicIsize,
+    num_alive: usize,
+    peak_alive: usize,
 }
 
 impl Entities {
-    pub fn new() -> Self {
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
-            len: AtomicUsize::new(0),
-            meta: vec![],
+            meta: Vec::with_capacity(capacity),
+            generations: Vec::with_capacity(capacity),
+            free_list: Vec::new(),
+            free_cursor: AtomicIsize::new(0),
+            num_alive: 0,
+            peak_alive: 0,
         }
     }
 
@@ -26,35 +82,105 @@ impl Entities {
         &mut self,
         mut do_archetype_stuf: impl FnMut(Entity),
     ) -> NoReservedEntities<'_> {
-        let new_len = *self.len.get_mut();
-        for id in self.meta.len()..new_len {
-            do_archetype_stuf(Entity(id));
+        let free_cursor = *self.free_cursor.get_mut();
+        let num_reused = self.free_list.len() - isize::max(free_cursor, 0) as usize;
+        for _ in 0..num_reused {
+            let idx = self.free_list.pop().unwrap();
+            self.meta[idx as usize] = Some(EntityMeta { archetype: 0 });
+            do_archetype_stuf(Entity::new(idx, self.generations[idx as usize]));
+        }
+
+        let num_new = isize::max(-free_cursor, 0) as usize;
+        for _ in 0..num_new {
+            // reserved entities in new slots always start at generation 0
+            do_archetype_stuf(Entity::new(self.meta.len() as u32, 0));
+            self.meta.push(Some(EntityMeta { archetype: 0 }));
+            self.generations.push(0);
         }
-        self.meta.resize(new_len, Some(EntityMeta { archetype: 0 }));
+
+        *self.free_cursor.get_mut() = self.free_list.len() as isize;
+        self.num_alive += num_reused + num_new;
+        self.peak_alive = usize::max(self.peak_alive, self.num_alive);
         NoReservedEntities(self)
     }
 
+    /// Reuses a despawned slot if there are any left that haven't been reserved already,
+    /// otherwise reserves a new slot.
     pub fn reserve_entity(&self) -> Entity {
-        let id = self.len.fetch_add(1, Ordering::Relaxed);
-        if let usize::MAX = id {
-            panic!("too many entities spawned (> usize::MAX)");
+        let n = self.free_cursor.fetch_sub(1, Ordering::Relaxed);
+        if n > 0 {
+            let idx = self.free_list[n as usize - 1];
+            return Entity::new(idx, self.generations[idx as usize]);
+        }
+
+        let id = self.meta.len() + n.unsigned_abs();
+        if id >= u32::MAX as usize {
+            panic!("too many entities spawned (> u32::MAX)");
+        }
+        Entity::new(id as u32, 0)
+    }
+
+    /// Entities reserved since the last `fix_reserved_entities`
+    pub fn num_reserved(&self) -> usize {
+        (self.free_list.len() as isize - self.free_cursor.load(Ordering::Relaxed)) as usize
+    }
+
+    /// The most entities that have been alive at once
+    pub fn peak_alive(&self) -> usize {
+        self.peak_alive
+    }
+
+    /// Number of entity slots that have been made real, including slots whose entity has
+    /// been despawned. Despawned slots are reused so this is at most the peak number of
+    /// alive and reserved entities.
+    pub fn num_materialized(&self) -> usize {
+        self.meta.len()
+    }
+
+    /// Reserved but not yet made alive by `fix_reserved_entities`
+    pub fn is_reserved(&self, entity: Entity) -> bool {
+        let free_cursor = self.free_cursor.load(Ordering::Relaxed);
+        match self.meta.get(entity.index()) {
+            // a despawned slot that has been reserved for reuse
+            Some(None) => {
+                self.generations[entity.index()] == entity.generation
+                    && self.free_list[isize::max(free_cursor, 0) as usize..].contains(&entity.index)
+            }
+            Some(Some(_)) => false,
+            None => {
+                entity.generation == 0
+                    && entity.index() < self.meta.len() + isize::max(-free_cursor, 0) as usize
+            }
         }
-        Entity(id)
     }
 
     pub fn is_alive(&self, entity: Entity) -> bool {
-        self.meta
-            .get(entity.0)
-            .map(|meta| meta.is_some())
-            .unwrap_or(false)
+        self.meta(entity).is_some()
     }
 
+    /// `None` if the entity is dead or is from an older generation of its slot
     pub fn meta(&self, entity: Entity) -> Option<&EntityMeta> {
-        self.meta.get(entity.0).and_then(Option::as_ref)
+        if self.generations.get(entity.index()) != Some(&entity.generation) {
+            return None;
+        }
+        self.meta[entity.index()].as_ref()
     }
 
     pub(crate) fn meta_mut(&mut self, entity: Entity) -> Option<&mut EntityMeta> {
-        self.meta.get_mut(entity.0).and_then(Option::as_mut)
+        if self.generations.get(entity.index()) != Some(&entity.generation) {
+            return None;
+        }
+        self.meta[entity.index()].as_mut()
+    }
+
+    /// Every live entity in ascending index order
+    pub(crate) fn iter_alive(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.meta
+            .iter()
+            .zip(self.generations.iter())
+            .enumerate()
+            .filter(|(_, (meta, _))| meta.is_some())
+            .map(|(idx, (_, &generation))| Entity::new(idx as u32, generation))
     }
 
     pub fn spawn(&mut self, mut do_archetype_stuff: impl FnMut(Entity)) -> Entity {
@@ -69,8 +195,128 @@ pub(crate) struct NoReservedEntities<'a>(&'a mut Entities);
 impl<'a> NoReservedEntities<'a> {
     pub fn despawn(&mut self, entity: Entity, handle_despawn: impl FnOnce(EntityMeta)) {
         if self.0.is_alive(entity) {
-            handle_despawn(self.0.meta[entity.0].unwrap());
-            self.0.meta[entity.0] = None;
+            handle_despawn(self.0.meta[entity.index()].unwrap());
+            self.0.meta[entity.index()] = None;
+            self.0.generations[entity.index()] = entity.generation.wrapping_add(1);
+            self.0.free_list.push(entity.index);
+            *self.0.free_cursor.get_mut() = self.0.free_list.len() as isize;
+            self.0.num_alive -= 1;
+        }
+    }
+
+    /// Despawns every entity, bumping the generation of every slot that was alive
+    pub fn despawn_all(&mut self) {
+        let entities = &mut *self.0;
+        for (idx, (meta, generation)) in entities
+            .meta
+            .iter_mut()
+            .zip(entities.generations.iter_mut())
+            .enumerate()
+        {
+            if meta.take().is_some() {
+                *generation = generation.wrapping_add(1);
+                entities.free_list.push(idx as u32);
+            }
+        }
+        *entities.free_cursor.get_mut() = entities.free_list.len() as isize;
+        entities.num_alive = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entity_fmt() {
+        assert_eq!(format!("{}", Entity::new(12, 3)), "e12v3");
+        assert_eq!(
+            format!("{:?}", Entity::new(12, 3)),
+            "Entity { index: 12, generation: 3 }"
+        );
+    }
+
+    #[test]
+    fn entity_index() {
+        let mut entities = Entities::with_capacity(0);
+        let e1 = entities.spawn(|_| ());
+        let e2 = entities.spawn(|_| ());
+        assert_eq!(e1.index(), 0);
+        assert_eq!(e2.index(), 1);
+        assert!(e1.same_index(e1));
+        assert!(!e1.same_index(e2));
+    }
+
+    #[test]
+    fn stale_generation() {
+        let mut entities = Entities::with_capacity(0);
+        let e1 = entities.spawn(|_| ());
+        let e2 = entities.spawn(|_| ());
+        let stale = Entity::new(e1.index, e1.generation + 1);
+        assert!(!entities.is_alive(stale));
+        assert!(entities.meta(stale).is_none());
+        assert!(stale.same_index(e1) && stale != e1);
+
+        entities.fix_reserved_entities(|_| ()).despawn(e1, |_| ());
+        assert!(!entities.is_alive(e1));
+        // despawning a stale handle doesn't despawn the current entity in the slot
+        entities
+            .fix_reserved_entities(|_| ())
+            .despawn(Entity::new(e2.index, 1), |_| unreachable!());
+        assert!(entities.is_alive(e2));
+        assert_eq!(entities.iter_alive().collect::<Vec<_>>(), [e2]);
+    }
+
+    #[test]
+    fn reuse_despawned() {
+        let mut entities = Entities::with_capacity(0);
+        for _ in 0..100 {
+            let e = entities.spawn(|_| ());
+            entities.fix_reserved_entities(|_| ()).despawn(e, |_| ());
+        }
+        assert_eq!(entities.num_materialized(), 1);
+
+        let e1 = entities.spawn(|_| ());
+        assert_eq!((e1.index(), e1.generation()), (0, 100));
+        let e2 = entities.spawn(|_| ());
+        assert_eq!((e2.index(), e2.generation()), (1, 0));
+    }
+
+    #[test]
+    fn reserve_with_free_list() {
+        let mut entities = Entities::with_capacity(0);
+        let spawned = (0..3).map(|_| entities.spawn(|_| ())).collect::<Vec<_>>();
+        let mut no_reserved = entities.fix_reserved_entities(|_| ());
+        no_reserved.despawn(spawned[0], |_| ());
+        no_reserved.despawn(spawned[2], |_| ());
+
+        let reserved = (0..4)
+            .map(|_| entities.reserve_entity())
+            .collect::<Vec<_>>();
+        assert_eq!(entities.num_reserved(), 4);
+        assert_eq!(
+            reserved.iter().map(|e| e.index()).collect::<Vec<_>>(),
+            [2, 0, 3, 4]
+        );
+        for &e in &reserved {
+            assert!(entities.is_reserved(e) && !entities.is_alive(e));
+        }
+        assert!(!entities.is_reserved(spawned[0]));
+        assert!(!entities.is_reserved(spawned[1]));
+        assert!(!entities.is_reserved(Entity::new(5, 0)));
+
+        let mut made_alive = vec![];
+        entities.fix_reserved_entities(|e| made_alive.push(e));
+        made_alive.sort();
+        let mut expected = reserved.clone();
+        expected.sort();
+        assert_eq!(made_alive, expected);
+        assert_eq!(entities.num_reserved(), 0);
+        assert_eq!(entities.num_materialized(), 5);
+        for &e in &reserved {
+            assert!(entities.is_alive(e) && !entities.is_reserved(e));
         }
+        assert!(!entities.is_alive(spawned[0]));
+        assert!(entities.is_alive(spawned[1]));
     }
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::{Component, Entity, World};
+
+#[derive(Component, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Parent(pub Entity);
+
+/// Keeps an index of each entity's children in sync with the `Parent` components
+/// in a `World`. `Parent` should only be inserted/removed through `set_parent` and
+/// `clear_parent` otherwise the index will get out of sync.
+///
+/// Despawning an entity does not update the index, call `clear_parent` on it first or
+/// its parent's `children` will still contain it.
+#[derive(Debug, Default)]
+pub struct Hierarchy {
+    children: HashMap<Entity, Vec<Entity>>,
+}
+
+impl Hierarchy {
+    pub fn new() -> Self {
+        Self {
+            children: HashMap::new(),
+        }
+    }
+
+    pub fn set_parent(&mut self, world: &mut World, child: Entity, parent: Entity) {
+        if world.is_alive(child) == false {
+            return;
+        }
+        self.clear_parent(world, child);
+        world.insert_component(child, Parent(parent));
+        self.children.entry(parent).or_default().push(child);
+    }
+
+    pub fn clear_parent(&mut self, world: &mut World, child: Entity) -> Option<Entity> {
+        let Parent(parent) = world.remove_component::<Parent>(child)?;
+        // `Parent` may have been inserted without going through this `Hierarchy`
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|&sibling| sibling != child);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+        Some(parent)
+    }
+
+    pub fn parent(&self, world: &World, child: Entity) -> Option<Entity> {
+        world.get_component::<Parent>(child).map(|parent| parent.0)
+    }
+
+    pub fn children(&self, parent: Entity) -> &[Entity] {
+        self.children
+            .get(&parent)
+            .map(|children| children.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reparent() {
+        let mut world = World::new();
+        let mut hierarchy = Hierarchy::new();
+        let p1 = world.spawn().id();
+        let p2 = world.spawn().id();
+        let c1 = world.spawn().id();
+        let c2 = world.spawn().id();
+
+        hierarchy.set_parent(&mut world, c1, p1);
+        hierarchy.set_parent(&mut world, c2, p1);
+        assert_eq!(hierarchy.children(p1), &[c1, c2]);
+        assert_eq!(hierarchy.parent(&world, c1), Some(p1));
+
+        hierarchy.set_parent(&mut world, c1, p2);
+        assert_eq!(hierarchy.children(p1), &[c2]);
+        assert_eq!(hierarchy.children(p2), &[c1]);
+        assert_eq!(hierarchy.parent(&world, c1), Some(p2));
+
+        assert_eq!(hierarchy.clear_parent(&mut world, c2), Some(p1));
+        assert_eq!(hierarchy.children(p1), &[]);
+        assert_eq!(hierarchy.parent(&world, c2), None);
+        assert_eq!(hierarchy.clear_parent(&mut world, c2), None);
+    }
+
+    #[test]
+    fn parent_inserted_directly() {
+        let mut world = World::new();
+        let mut hierarchy = Hierarchy::new();
+        let p = world.spawn().id();
+        let c = world.spawn().insert(Parent(p)).id();
+
+        assert_eq!(hierarchy.children(p), &[]);
+        assert_eq!(hierarchy.clear_parent(&mut world, c), Some(p));
+        assert_eq!(hierarchy.parent(&world, c), None);
+
+        // the parent was set through a different `Hierarchy`
+        Hierarchy::new().set_parent(&mut world, c, p);
+        let p2 = world.spawn().id();
+        hierarchy.set_parent(&mut world, c, p2);
+        assert_eq!(hierarchy.children(p2), &[c]);
+        assert_eq!(hierarchy.parent(&world, c), Some(p2));
+    }
+}
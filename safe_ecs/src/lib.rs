@@ -4,22 +4,34 @@
 mod commands;
 mod dynamic_storage;
 mod entities;
+mod hierarchy;
 mod query;
 mod scope;
+mod sparse_set;
 mod system;
 mod world;
 
 pub use commands::{Command, CommandBuffer, Commands, CommandsWithEntity};
 pub use entities::Entity;
-pub use query::{DynQueryParam, DynQueryParamKind, Maybe, Query, QueryIter};
+pub use hierarchy::{Hierarchy, Parent};
+pub use query::{
+    AnyOf, ArchetypeQueryIter, DynQueryParam, DynQueryParamKind, Lens, Map, Mapper, Maybe,
+    PreparedQuery, Projection, Query, QueryArchetypeIter, QueryIter, Sparse,
+};
 pub use safe_ecs_derive::Component;
 pub use scope::Scope;
-pub use system::{Access, System, SystemParam, ToSystem};
-pub use world::{Component, EcsTypeId, EntityBuilder, World};
+pub use sparse_set::SparseSet;
+pub use system::{exclusive_system, Access, Local, System, SystemParam, ToSystem};
+pub use world::{Archetype, Bundle, Component, EcsTypeId, EntityBuilder, StorageKind, World};
 
 pub mod errors {
     #[derive(Debug, Copy, Clone)]
     pub struct WorldBorrowError(pub &'static str);
+
+    /// A system's params access the same component in conflicting ways,
+    /// e.g. `Query<&mut T>` twice. The contained string is the system's `System::name`.
+    #[derive(Debug, Clone)]
+    pub struct AccessError(pub String);
 }
 
 use std::marker::PhantomData;
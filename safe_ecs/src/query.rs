@@ -2,7 +2,7 @@ use crate::{
     errors::WorldBorrowError,
     system::Access,
     world::{Archetype, EcsTypeId, Storage},
-    Component, Entity, World,
+    Component, Entity, SparseSet, World,
 };
 use std::{
     any::{type_name, TypeId},
@@ -11,6 +11,9 @@ use std::{
     marker::PhantomData,
 };
 
+const SPARSE_IN_COLUMN_PARAM: &str =
+    "components registered as sparse must be queried with `Sparse`";
+
 pub trait QueryParam: 'static {
     type Lock<'a>
     where
@@ -110,6 +113,9 @@ impl<T: Component> QueryParam for &'static T {
             None => return Ok(None),
             Some(ecs_type_id) => ecs_type_id,
         };
+        if world.sparse_sets.contains_key(ecs_type_id) {
+            return Err(WorldBorrowError(SPARSE_IN_COLUMN_PARAM));
+        }
 
         world
             .columns
@@ -168,6 +174,9 @@ impl<T: Component> QueryParam for &'static mut T {
             Some(id) => id,
             None => return Ok(None),
         };
+        if world.sparse_sets.contains_key(ecs_type_id) {
+            return Err(WorldBorrowError(SPARSE_IN_COLUMN_PARAM));
+        }
 
         world
             .columns
@@ -340,6 +349,206 @@ impl<Q: QueryParam> QueryParam for Maybe<Q> {
     }
 }
 
+/// Matches archetypes that match at least one of the params in the tuple `T`,
+/// yielding a tuple of `Option`s like `(Maybe<A>, Maybe<B>, ..)` would.
+pub struct AnyOf<T>(PhantomData<T>);
+
+macro_rules! any_of_tuple_impl {
+    ($($T:ident)+) => {
+        impl<$($T: QueryParam),+> QueryParam for AnyOf<($($T,)+)> {
+            type Lock<'a> = <($(Maybe<$T>,)+) as QueryParam>::Lock<'a>;
+            type LockBorrow<'a> = <($(Maybe<$T>,)+) as QueryParam>::LockBorrow<'a>;
+            type Item<'a> = <($(Maybe<$T>,)+) as QueryParam>::Item<'a>;
+            type ItemIter<'a> = <($(Maybe<$T>,)+) as QueryParam>::ItemIter<'a>;
+
+            fn lock_from_world(world: &World) -> Result<Option<Self::Lock<'_>>, WorldBorrowError> {
+                <($(Maybe<$T>,)+)>::lock_from_world(world)
+            }
+
+            fn lock_borrows_from_locks<'a, 'b>(lock: &'a mut Self::Lock<'b>) -> Self::LockBorrow<'a> {
+                <($(Maybe<$T>,)+)>::lock_borrows_from_locks(lock)
+            }
+
+            fn archetype_matches(archetype: &Archetype, ecs_type_ids: &HashMap<TypeId, EcsTypeId>) -> bool {
+                $($T::archetype_matches(archetype, ecs_type_ids))||+
+            }
+
+            fn item_iter_from_archetype<'a>(
+                archetype: &'a Archetype,
+                lock_borrow: &mut Self::LockBorrow<'a>,
+                ecs_type_ids: &HashMap<TypeId, EcsTypeId>,
+            ) -> Self::ItemIter<'a> {
+                <($(Maybe<$T>,)+)>::item_iter_from_archetype(archetype, lock_borrow, ecs_type_ids)
+            }
+
+            fn advance_iter<'a>(iter: &mut Self::ItemIter<'a>) -> Option<Self::Item<'a>> {
+                <($(Maybe<$T>,)+)>::advance_iter(iter)
+            }
+
+            fn get_access() -> Result<Access, ()> {
+                <($(Maybe<$T>,)+)>::get_access()
+            }
+        }
+    };
+}
+
+any_of_tuple_impl!(A B C D E F G H);
+any_of_tuple_impl!(A B C D E F G);
+any_of_tuple_impl!(A B C D E F);
+any_of_tuple_impl!(A B C D E);
+any_of_tuple_impl!(A B C D);
+any_of_tuple_impl!(A B C);
+any_of_tuple_impl!(A B);
+any_of_tuple_impl!(A);
+
+/// Projects a component to one of its fields, used by the `Lens` query param.
+pub trait Projection: 'static {
+    type Source: Component;
+    type Target: 'static;
+    fn project(source: &mut Self::Source) -> &mut Self::Target;
+}
+
+/// Yields `&mut P::Target` for every `P::Source` component. Access is still tracked
+/// for the whole of `P::Source` so two lenses of the same component conflict.
+pub struct Lens<P: Projection>(PhantomData<P>);
+impl<P: Projection> QueryParam for Lens<P> {
+    type Lock<'a> = <&'static mut P::Source as QueryParam>::Lock<'a>;
+    type LockBorrow<'a> = <&'static mut P::Source as QueryParam>::LockBorrow<'a>;
+    type Item<'a> = &'a mut P::Target;
+    type ItemIter<'a> = <&'static mut P::Source as QueryParam>::ItemIter<'a>;
+
+    fn lock_from_world(world: &World) -> Result<Option<Self::Lock<'_>>, WorldBorrowError> {
+        <&mut P::Source>::lock_from_world(world)
+    }
+
+    fn lock_borrows_from_locks<'a, 'b>(lock: &'a mut Self::Lock<'b>) -> Self::LockBorrow<'a> {
+        <&mut P::Source>::lock_borrows_from_locks(lock)
+    }
+
+    fn archetype_matches(archetype: &Archetype, ecs_type_ids: &HashMap<TypeId, EcsTypeId>) -> bool {
+        <&mut P::Source>::archetype_matches(archetype, ecs_type_ids)
+    }
+
+    fn item_iter_from_archetype<'a>(
+        archetype: &'a Archetype,
+        lock_borrow: &mut Self::LockBorrow<'a>,
+        ecs_type_ids: &HashMap<TypeId, EcsTypeId>,
+    ) -> Self::ItemIter<'a> {
+        <&mut P::Source>::item_iter_from_archetype(archetype, lock_borrow, ecs_type_ids)
+    }
+
+    fn advance_iter<'a>(iter: &mut Self::ItemIter<'a>) -> Option<Self::Item<'a>> {
+        iter.next().map(P::project)
+    }
+
+    fn get_access() -> Result<Access, ()> {
+        <&mut P::Source>::get_access()
+    }
+}
+
+/// Maps the items of a query param to another value, used by the `Map` query param.
+/// `Output` can't borrow from the item, use `Lens` to get a reference to a field.
+pub trait Mapper: 'static {
+    type Param: QueryParam;
+    type Output;
+    fn map(item: <Self::Param as QueryParam>::Item<'_>) -> Self::Output;
+}
+
+/// Yields `M::map` of every `M::Param` item. Matches the same archetypes and has the same
+/// access as `M::Param`.
+pub struct Map<M: Mapper>(PhantomData<M>);
+impl<M: Mapper> QueryParam for Map<M> {
+    type Lock<'a> = <M::Param as QueryParam>::Lock<'a>;
+    type LockBorrow<'a> = <M::Param as QueryParam>::LockBorrow<'a>;
+    type Item<'a> = M::Output;
+    type ItemIter<'a> = <M::Param as QueryParam>::ItemIter<'a>;
+
+    fn lock_from_world(world: &World) -> Result<Option<Self::Lock<'_>>, WorldBorrowError> {
+        M::Param::lock_from_world(world)
+    }
+
+    fn lock_borrows_from_locks<'a, 'b>(lock: &'a mut Self::Lock<'b>) -> Self::LockBorrow<'a> {
+        M::Param::lock_borrows_from_locks(lock)
+    }
+
+    fn archetype_matches(archetype: &Archetype, ecs_type_ids: &HashMap<TypeId, EcsTypeId>) -> bool {
+        M::Param::archetype_matches(archetype, ecs_type_ids)
+    }
+
+    fn item_iter_from_archetype<'a>(
+        archetype: &'a Archetype,
+        lock_borrow: &mut Self::LockBorrow<'a>,
+        ecs_type_ids: &HashMap<TypeId, EcsTypeId>,
+    ) -> Self::ItemIter<'a> {
+        M::Param::item_iter_from_archetype(archetype, lock_borrow, ecs_type_ids)
+    }
+
+    fn advance_iter<'a>(iter: &mut Self::ItemIter<'a>) -> Option<Self::Item<'a>> {
+        M::Param::advance_iter(iter).map(M::map)
+    }
+
+    fn get_access() -> Result<Access, ()> {
+        M::Param::get_access()
+    }
+}
+
+/// Yields `Some(&T)` for entities that have a `T` in its sparse set and `None` otherwise,
+/// like `Maybe<&T>` does for archetype components. Errors if `T` is registered as
+/// `StorageKind::Table` since its components would never be found in the sparse set.
+pub struct Sparse<Q>(PhantomData<Q>);
+impl<T: Component> QueryParam for Sparse<&'static T> {
+    type Lock<'a> = Option<cell::Ref<'a, SparseSet<T>>>;
+    type LockBorrow<'a> = Option<&'a SparseSet<T>>;
+    type Item<'a> = Option<&'a T>;
+    type ItemIter<'a> = (std::slice::Iter<'a, Entity>, Option<&'a SparseSet<T>>);
+
+    fn lock_from_world(world: &World) -> Result<Option<Self::Lock<'_>>, WorldBorrowError> {
+        let ecs_type_id = match world.ecs_type_ids.get(&TypeId::of::<T>()) {
+            None => return Ok(Some(None)),
+            Some(ecs_type_id) => ecs_type_id,
+        };
+        let set = match world.sparse_sets.get(ecs_type_id) {
+            None => {
+                return Err(WorldBorrowError(
+                    "`Sparse` used on a component stored in archetypes",
+                ))
+            }
+            Some(set) => set,
+        };
+        let set = set
+            .try_borrow()
+            .map_err(|_| WorldBorrowError(type_name::<T>()))?;
+        Ok(Some(Some(cell::Ref::map(set, |set| {
+            set.downcast_ref::<T>()
+        }))))
+    }
+
+    fn lock_borrows_from_locks<'a, 'b>(lock: &'a mut Self::Lock<'b>) -> Self::LockBorrow<'a> {
+        lock.as_deref()
+    }
+
+    fn archetype_matches(_: &Archetype, _: &HashMap<TypeId, EcsTypeId>) -> bool {
+        true
+    }
+
+    fn item_iter_from_archetype<'a>(
+        archetype: &'a Archetype,
+        lock_borrow: &mut Self::LockBorrow<'a>,
+        _: &HashMap<TypeId, EcsTypeId>,
+    ) -> Self::ItemIter<'a> {
+        (archetype.entities.iter(), *lock_borrow)
+    }
+
+    fn advance_iter<'a>((entities, set): &mut Self::ItemIter<'a>) -> Option<Self::Item<'a>> {
+        let entity = *entities.next()?;
+        Some(set.and_then(|set| set.get(entity)))
+    }
+
+    fn get_access() -> Result<Access, ()> {
+        Access::new().insert_read(TypeId::of::<T>())
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct DynQueryParam {
     id: EcsTypeId,
@@ -360,6 +569,13 @@ impl DynQueryParam {
             kind: DynQueryParamKind::Mut,
         }
     }
+
+    pub fn get_access(&self) -> Result<Access, ()> {
+        match self.kind {
+            DynQueryParamKind::Mut => Access::new().insert_write_dynamic(self.id),
+            DynQueryParamKind::Ref => Access::new().insert_read_dynamic(self.id),
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -382,6 +598,7 @@ pub struct Query<'a, Q: QueryParam + 'static> {
     pub(crate) w: &'a World,
     pub(crate) locks: Option<(Q::Lock<'a>, Vec<DynQueryParamLock<'a>>)>,
     pub(crate) dyn_params: Vec<DynQueryParam>,
+    pub(crate) dyn_access: Access,
 }
 
 // TODO add `DynQueryParam::MaybeMut/Ref`
@@ -392,22 +609,147 @@ impl<'b, Q: QueryParam> Query<'b, Q> {
         QueryIter::new(self)
     }
 
+    /// Iterates the query one archetype at a time, yielding an iterator over the
+    /// items of each matching archetype. Dynamic params only filter which archetypes
+    /// are visited, their data is not yielded.
+    pub fn iter_by_archetype_mut(&mut self) -> QueryArchetypeIter<'_, 'b, Q> {
+        QueryArchetypeIter(QueryIter::new(self))
+    }
+
     pub fn add_dyn_param(&mut self, param: DynQueryParam) -> &mut Self {
-        self.dyn_params.push(param);
+        self.try_add_dyn_param(param).unwrap()
+    }
+
+    /// Errors if `param` conflicts with a dynamic param already added to this query,
+    /// if its column is locked elsewhere, or if its `EcsTypeId` is out of range for this world.
+    /// Ids don't record which world created them, so an id from another world that is in
+    /// range is not caught.
+    pub fn try_add_dyn_param(
+        &mut self,
+        param: DynQueryParam,
+    ) -> Result<&mut Self, WorldBorrowError> {
+        if self.w.columns.contains_key(&param.id) == false {
+            return Err(WorldBorrowError("unknown EcsTypeId"));
+        }
+        if self.w.sparse_sets.contains_key(&param.id) {
+            return Err(WorldBorrowError(SPARSE_IN_COLUMN_PARAM));
+        }
+        let access = self
+            .dyn_access
+            .clone()
+            .join_with(param.get_access())
+            .map_err(|_| WorldBorrowError("conflicting dynamic query params"))?;
+
         if let Some((_, dyn_locks)) = &mut self.locks {
-            match param.kind {
-                DynQueryParamKind::Mut => dyn_locks.push(DynQueryParamLock::Mut(
-                    self.w.columns[&param.id].borrow_mut(),
-                )),
-                DynQueryParamKind::Ref => {
-                    dyn_locks.push(DynQueryParamLock::Ref(self.w.columns[&param.id].borrow()))
-                }
+            let column = &self.w.columns[&param.id];
+            dyn_locks.push(match param.kind {
+                DynQueryParamKind::Mut => DynQueryParamLock::Mut(
+                    column
+                        .try_borrow_mut()
+                        .map_err(|_| WorldBorrowError("dynamic component"))?,
+                ),
+                DynQueryParamKind::Ref => DynQueryParamLock::Ref(
+                    column
+                        .try_borrow()
+                        .map_err(|_| WorldBorrowError("dynamic component"))?,
+                ),
+            });
+        }
+        self.dyn_access = access;
+        self.dyn_params.push(param);
+
+        Ok(self)
+    }
+}
+impl<'b, T: Component> Query<'b, &'static mut T> {
+    /// Returns mutable references to the `T` component of each of `entities` at once,
+    /// `None` for entities that are not alive or do not have a `T`.
+    ///
+    /// Panics if any entity is in `entities` more than once.
+    pub fn get_disjoint_mut<const N: usize>(
+        &mut self,
+        entities: [Entity; N],
+    ) -> [Option<&mut T>; N] {
+        for (idx, entity) in entities.iter().enumerate() {
+            assert!(
+                entities[..idx].contains(entity) == false,
+                "entity {} passed to `get_disjoint_mut` more than once",
+                entity
+            );
+        }
+
+        let mut out = [(); N].map(|_| None);
+        let (lock, _) = match &mut self.locks {
+            Some(locks) => locks,
+            None => return out,
+        };
+        let ecs_type_id = self.w.ecs_type_ids[&TypeId::of::<T>()];
+
+        // (column, row, index into `out`)
+        let mut rows = entities
+            .iter()
+            .enumerate()
+            .filter_map(|(out_idx, &entity)| {
+                let archetype = &self.w.archetypes[self.w.entities.meta(entity)?.archetype];
+                let column = *archetype.column_indices.get(&ecs_type_id)?;
+                Some((column, archetype.get_entity_idx(entity).unwrap(), out_idx))
+            })
+            .collect::<Vec<_>>();
+        rows.sort();
+
+        let mut rows = rows.into_iter().peekable();
+        for (column_idx, storage) in lock.iter_mut().enumerate() {
+            if rows
+                .peek()
+                .map_or(true, |&(column, _, _)| column != column_idx)
+            {
+                continue;
+            }
+            let mut remaining = storage
+                .as_typed_storage_mut()
+                .unwrap()
+                .as_vec_mut::<T>()
+                .unwrap()
+                .as_mut_slice();
+            let mut num_chopped_off = 0;
+            while let Some((_, row, out_idx)) = rows.next_if(|&(column, _, _)| column == column_idx)
+            {
+                let (_, tail) = std::mem::take(&mut remaining).split_at_mut(row - num_chopped_off);
+                let (component, tail) = tail.split_first_mut().unwrap();
+                out[out_idx] = Some(component);
+                remaining = tail;
+                num_chopped_off = row + 1;
             }
         }
+        out
+    }
 
-        self
+    /// Splits every archetype's `T` column into disjoint chunks of at most `chunk_size`
+    /// components, chunks never span two archetypes. The chunks can be handed out to
+    /// separate threads to process a large archetype in parallel.
+    ///
+    /// Panics if `chunk_size` is zero or if any dynamic params have been added to the query.
+    pub fn par_chunks(&mut self, chunk_size: usize) -> impl Iterator<Item = &mut [T]> + '_ {
+        assert!(chunk_size != 0, "chunk size must be non-zero");
+        assert!(
+            self.dyn_params.is_empty(),
+            "`par_chunks` does not support dynamic query params"
+        );
+        let storages = match &mut self.locks {
+            Some((lock, _)) => lock.as_mut_slice(),
+            None => &mut [],
+        };
+        storages.iter_mut().flat_map(move |storage| {
+            storage
+                .as_typed_storage_mut()
+                .unwrap()
+                .as_vec_mut::<T>()
+                .unwrap()
+                .chunks_mut(chunk_size)
+        })
     }
 }
+
 impl<'a, 'b: 'a, Q: QueryParam> IntoIterator for &'a mut Query<'b, Q> {
     type Item = Q::Item<'a>;
     type IntoIter = QueryIter<'a, 'b, Q>;
@@ -424,14 +766,18 @@ pub struct QueryIter<'a, 'b: 'a, Q: QueryParam> {
     borrows: Option<(Q::LockBorrow<'a>, Vec<DynQueryParamLockBorrow<'a>>)>,
     archetype_iter: ArchetypeIter<'a, 'b, Q>,
     item_iters: Option<(Q::ItemIter<'a>, Vec<Box<dyn Iterator<Item = *mut u8> + 'a>>)>,
+    /// Index of the archetype `item_iters` was created from
+    item_iters_archetype: usize,
+    current_archetype: Option<usize>,
 
     dyn_params: &'a [DynQueryParam],
     dyn_param_data_ptrs: Vec<*mut u8>,
 }
 
-type ArchetypeIter<'a, 'b: 'a, Q> = impl Iterator<Item = &'b Archetype> + 'a;
+type ArchetypeIter<'a, 'b: 'a, Q: QueryParam> = impl Iterator<Item = (usize, &'b Archetype)> + 'a;
 impl<'a, 'b: 'a, Q: QueryParam> QueryIter<'a, 'b, Q> {
     fn new(borrows: &'a mut Query<'b, Q>) -> Self {
+        #[define_opaque(ArchetypeIter)]
         fn defining_use<'a, 'b: 'a, Q: QueryParam>(
             world: &'b World,
             dyn_params: &'a [DynQueryParam],
@@ -439,8 +785,11 @@ impl<'a, 'b: 'a, Q: QueryParam> QueryIter<'a, 'b, Q> {
             world
                 .archetypes
                 .iter()
-                .filter(|archetype| Q::archetype_matches(archetype, &world.ecs_type_ids))
-                .filter(|archetype| {
+                .enumerate()
+                // no point creating item iters for an archetype with no entities
+                .filter(|(_, archetype)| !archetype.entities.is_empty())
+                .filter(|(_, archetype)| Q::archetype_matches(archetype, &world.ecs_type_ids))
+                .filter(|(_, archetype)| {
                     dyn_params.iter().all(|param| {
                         use DynQueryParamKind::*;
                         match &param.kind {
@@ -468,6 +817,8 @@ impl<'a, 'b: 'a, Q: QueryParam> QueryIter<'a, 'b, Q> {
                 )
             }),
             item_iters: None,
+            item_iters_archetype: 0,
+            current_archetype: None,
 
             dyn_params: &borrows.dyn_params[..],
             dyn_param_data_ptrs: vec![std::ptr::null_mut(); borrows.dyn_params.len()],
@@ -481,7 +832,8 @@ impl<'a, 'b: 'a, Q: QueryParam> Iterator for QueryIter<'a, 'b, Q> {
         let (borrows, dyn_borrows) = self.borrows.as_mut()?;
         'outer: loop {
             if let None = &self.item_iters {
-                let archetype = self.archetype_iter.next()?;
+                let (archetype_idx, archetype) = self.archetype_iter.next()?;
+                self.item_iters_archetype = archetype_idx;
                 self.item_iters = Some((
                     Q::item_iter_from_archetype(archetype, borrows, self.ecs_type_ids),
                     self.dyn_params
@@ -532,26 +884,460 @@ impl<'a, 'b: 'a, Q: QueryParam> Iterator for QueryIter<'a, 'b, Q> {
                             }
                         }
                     }
+                    self.current_archetype = Some(self.item_iters_archetype);
                     return Some(item);
                 }
                 None => self.item_iters = None,
             }
         }
     }
+
+    fn count(mut self) -> usize {
+        if self.borrows.is_none() {
+            return 0;
+        }
+
+        let mut count = 0;
+        if let Some((static_iters, _)) = &mut self.item_iters {
+            while Q::advance_iter(static_iters).is_some() {
+                count += 1;
+            }
+        }
+        count
+            + self
+                .archetype_iter
+                .map(|(_, archetype)| archetype.entities.len())
+                .sum::<usize>()
+    }
 }
 
 impl<'a, 'b: 'a, Q: QueryParam> QueryIter<'a, 'b, Q> {
+    /// The index of the archetype that the most recently yielded item came from, `None`
+    /// if no items have been yielded yet.
+    pub fn current_archetype(&self) -> Option<usize> {
+        self.current_archetype
+    }
+
     pub fn next_dynamic(&mut self) -> Option<(<Self as Iterator>::Item, &mut [*mut u8])> {
         self.next()
             .map(|item| (item, &mut self.dyn_param_data_ptrs[..]))
     }
 }
 
+pub struct QueryArchetypeIter<'a, 'b: 'a, Q: QueryParam>(QueryIter<'a, 'b, Q>);
+
+impl<'a, 'b: 'a, Q: QueryParam> Iterator for QueryArchetypeIter<'a, 'b, Q> {
+    type Item = ArchetypeQueryIter<'a, Q>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let iter = &mut self.0;
+        let (borrows, _) = iter.borrows.as_mut()?;
+        let (_, archetype) = iter.archetype_iter.next()?;
+        Some(ArchetypeQueryIter(Q::item_iter_from_archetype(
+            archetype,
+            borrows,
+            iter.ecs_type_ids,
+        )))
+    }
+}
+
+pub struct ArchetypeQueryIter<'a, Q: QueryParam>(Q::ItemIter<'a>);
+
+impl<'a, Q: QueryParam> Iterator for ArchetypeQueryIter<'a, Q> {
+    type Item = Q::Item<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        Q::advance_iter(&mut self.0)
+    }
+}
+
+/// Caches which archetypes match `Q` so that repeatedly running the same query only
+/// has to check archetypes created since it was last run. Using a `PreparedQuery` with a
+/// different `World` than last time is correct but resets the cache.
+pub struct PreparedQuery<Q: QueryParam> {
+    matched_archetypes: Vec<usize>,
+    archetypes_checked: usize,
+    archetype_removal_count: usize,
+    /// `World::id` of the world the cache was built from
+    world_id: Option<u64>,
+    _p: PhantomData<Q>,
+}
+
+impl<Q: QueryParam> PreparedQuery<Q> {
+    pub fn new() -> Self {
+        Self {
+            matched_archetypes: Vec::new(),
+            archetypes_checked: 0,
+            archetype_removal_count: 0,
+            world_id: None,
+            _p: PhantomData,
+        }
+    }
+
+    fn update_archetypes(&mut self, world: &World) {
+        if self.archetype_removal_count != world.archetype_removal_count
+            || self.world_id != Some(world.id)
+            || self.archetypes_checked > world.archetypes.len()
+        {
+            // archetype indices have changed or are from another world so the cache is useless
+            self.matched_archetypes.clear();
+            self.archetypes_checked = 0;
+            self.archetype_removal_count = world.archetype_removal_count;
+            self.world_id = Some(world.id);
+        }
+        for (idx, archetype) in world.archetypes[self.archetypes_checked..]
+            .iter()
+            .enumerate()
+        {
+            if Q::archetype_matches(archetype, &world.ecs_type_ids) {
+                self.matched_archetypes.push(self.archetypes_checked + idx);
+            }
+        }
+        self.archetypes_checked = world.archetypes.len();
+    }
+
+    pub fn for_each_mut(
+        &mut self,
+        world: &World,
+        mut f: impl for<'a> FnMut(Q::Item<'a>),
+    ) -> Result<(), WorldBorrowError> {
+        self.update_archetypes(world);
+        let mut lock = match Q::lock_from_world(world)? {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+        let mut borrows = Q::lock_borrows_from_locks(&mut lock);
+        for &archetype in self.matched_archetypes.iter() {
+            let mut iter = Q::item_iter_from_archetype(
+                &world.archetypes[archetype],
+                &mut borrows,
+                &world.ecs_type_ids,
+            );
+            while let Some(item) = Q::advance_iter(&mut iter) {
+                f(item);
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod static_tests {
     use super::*;
     use crate::world::*;
 
+    #[derive(Component, Debug, PartialEq)]
+    struct Transform {
+        position: u32,
+        scale: u64,
+    }
+
+    struct Position;
+    impl Projection for Position {
+        type Source = Transform;
+        type Target = u32;
+        fn project(source: &mut Transform) -> &mut u32 {
+            &mut source.position
+        }
+    }
+
+    #[test]
+    fn current_archetype() {
+        let mut world = World::new();
+        world.spawn().insert(1_u32);
+        world.spawn().insert(2_u32).insert(10_u64);
+        world.spawn().insert(3_u32);
+        world.spawn().insert(11_u64);
+        world.spawn().insert(4_u32).insert(12_u64);
+
+        let mut q = world.query::<(Entity, &u32)>().unwrap();
+        let mut iter = q.iter_mut();
+        assert_eq!(iter.current_archetype(), None);
+        let mut archetypes = vec![];
+        while let Some((entity, _)) = iter.next() {
+            let archetype = world.entities.meta(entity).unwrap().archetype;
+            assert_eq!(iter.current_archetype(), Some(archetype));
+            archetypes.push(archetype);
+        }
+        assert_eq!(archetypes.len(), 4);
+        assert!(archetypes.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(iter.current_archetype().is_some());
+    }
+
+    #[test]
+    fn unit_query_counts_entities() {
+        let mut world = World::new();
+        world.spawn();
+        let e2 = world.spawn().insert(10_u32).id();
+        world.spawn().insert(10_u32).insert(12_u64);
+        world.despawn(e2);
+        world.reserve_entity();
+
+        let mut q = world.query::<()>().unwrap();
+        assert_eq!(q.iter_mut().count(), 2);
+        assert_eq!(q.iter_mut().fold(0, |count, ()| count + 1), 2);
+        let mut q = world.query::<(Entity, ())>().unwrap();
+        assert_eq!(q.iter_mut().count(), 2);
+    }
+
+    #[test]
+    fn skips_empty_archetypes() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(10_u32).insert(1_u8).id();
+        let e2 = world.spawn().insert(12_u32).insert(1_u16).id();
+        let e3 = world.spawn().insert(14_u32).insert(1_u64).id();
+        world.despawn(e1);
+        world.remove_component::<u16>(e2);
+        world.despawn(e3);
+        let e4 = world.spawn().insert(16_u32).insert(1_u64).id();
+
+        let mut q = world.query::<(Entity, &mut u32)>().unwrap();
+        let mut found = q
+            .iter_mut()
+            .map(|(entity, value)| {
+                *value += 1;
+                (entity, *value)
+            })
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, vec![(e2, 13), (e4, 17)]);
+        assert_eq!(q.iter_mut().count(), 2);
+    }
+
+    #[test]
+    fn despawn_between_queries() {
+        let mut world = World::new();
+        let entities = (0..4_u32)
+            .map(|n| world.spawn().insert(n).insert(n as u64).id())
+            .collect::<Vec<_>>();
+
+        let mut q = world.query::<(Entity, &u32, &u64)>().unwrap();
+        assert_eq!(q.iter_mut().count(), 4);
+        drop(q);
+
+        world.despawn(entities[0]);
+        let mut q = world.query::<(Entity, &u32, &u64)>().unwrap();
+        let mut returned = q
+            .iter_mut()
+            .map(|(e, &a, &b)| (e, a, b))
+            .collect::<Vec<_>>();
+        returned.sort();
+        assert_eq!(
+            returned,
+            [
+                (entities[1], 1, 1),
+                (entities[2], 2, 2),
+                (entities[3], 3, 3)
+            ]
+        );
+        drop(q);
+
+        world.despawn(entities[3]);
+        world.despawn(entities[1]);
+        let mut q = world.query::<(Entity, &mut u32, &u64)>().unwrap();
+        let returned = q
+            .iter_mut()
+            .map(|(e, a, &b)| (e, *a, b))
+            .collect::<Vec<_>>();
+        assert_eq!(returned, [(entities[2], 2, 2)]);
+    }
+
+    #[test]
+    fn despawn_keeps_columns_in_sync() {
+        let mut world = World::new();
+        let entities = (0..5_u32)
+            .map(|n| world.spawn().insert(n).insert(n as u64).id())
+            .collect::<Vec<_>>();
+        world.despawn(entities[1]);
+        world.despawn(entities[0]);
+
+        for archetype in world.archetypes.iter() {
+            for (id, &column_idx) in archetype.column_indices.iter() {
+                let columns = world.columns[id].borrow();
+                let len = match columns[column_idx]
+                    .as_typed_storage()
+                    .unwrap()
+                    .as_vec::<u32>()
+                {
+                    Some(vec) => vec.len(),
+                    None => columns[column_idx]
+                        .as_typed_storage()
+                        .unwrap()
+                        .as_vec::<u64>()
+                        .unwrap()
+                        .len(),
+                };
+                assert_eq!(len, archetype.entities.len());
+            }
+        }
+        for &entity in &entities[2..] {
+            let value = *world.get_component::<u32>(entity).unwrap();
+            assert_eq!(*world.get_component::<u64>(entity).unwrap(), value as u64);
+        }
+    }
+
+    #[test]
+    fn prepared_query() {
+        let mut world = World::new();
+        world.spawn().insert(1_u32).insert(10_u64);
+        world.spawn().insert(2_u32);
+        world.spawn().insert(20_u64);
+
+        let mut prepared = PreparedQuery::<(Entity, &mut u32)>::new();
+        fn check(world: &World, prepared: &mut PreparedQuery<(Entity, &'static mut u32)>) -> usize {
+            let mut cached = vec![];
+            prepared
+                .for_each_mut(world, |(e, value)| {
+                    *value += 1;
+                    cached.push((e, *value));
+                })
+                .unwrap();
+            let mut q = world.query::<(Entity, &u32)>().unwrap();
+            let uncached = q.iter_mut().map(|(e, &v)| (e, v)).collect::<Vec<_>>();
+            assert_eq!(cached, uncached);
+            cached.len()
+        }
+        assert_eq!(check(&world, &mut prepared), 2);
+
+        // archetypes created after the first run are picked up
+        world.spawn().insert(3_u32).insert(true);
+        world.spawn().insert(true);
+        assert_eq!(check(&world, &mut prepared), 3);
+
+        // removing archetypes invalidates the cached indices
+        let e = world.spawn().insert(4_u32).insert(10_u8).id();
+        assert_eq!(check(&world, &mut prepared), 4);
+        world.despawn(e);
+        assert!(world.remove_empty_archetypes() > 0);
+        world.spawn().insert(5_u32).insert(10_u16);
+        assert_eq!(check(&world, &mut prepared), 4);
+
+        // using another world, with fewer or differently ordered archetypes, resets the cache
+        let mut other = World::new();
+        other.spawn().insert(10_u64).insert(1_u32);
+        assert_eq!(check(&other, &mut prepared), 1);
+        assert_eq!(check(&world, &mut prepared), 4);
+
+        // a world created in place of a dropped one is still a different world
+        let mut prepared = PreparedQuery::<(Entity, &mut u32)>::new();
+        other = World::new();
+        other.spawn().insert(1_u32);
+        assert_eq!(check(&other, &mut prepared), 1);
+        other = World::new();
+        other.spawn().insert(true);
+        other.spawn().insert(2_u32);
+        assert_eq!(check(&other, &mut prepared), 1);
+    }
+
+    #[test]
+    fn prepared_query_locked() {
+        let mut world = World::new();
+        world.spawn().insert(1_u32);
+        let mut prepared = PreparedQuery::<&mut u32>::new();
+        let _q = world.query::<&u32>().unwrap();
+        assert!(prepared.for_each_mut(&world, |_| ()).is_err());
+    }
+
+    #[test]
+    fn lens_query() {
+        let mut world = World::new();
+        let e1 = world
+            .spawn()
+            .insert(Transform {
+                position: 1,
+                scale: 10,
+            })
+            .id();
+        let e2 = world
+            .spawn()
+            .insert(Transform {
+                position: 2,
+                scale: 20,
+            })
+            .insert(12_u64)
+            .id();
+        world.spawn().insert(12_u64);
+
+        let mut q = world.query::<(Entity, Lens<Position>)>().unwrap();
+        let mut visited = vec![];
+        for (entity, position) in &mut q {
+            visited.push(entity);
+            *position += 100;
+        }
+        drop(q);
+        assert_eq!(visited, [e1, e2]);
+        assert_eq!(
+            *world.get_component::<Transform>(e1).unwrap(),
+            Transform {
+                position: 101,
+                scale: 10
+            }
+        );
+        assert_eq!(world.get_component::<Transform>(e2).unwrap().position, 102);
+
+        assert!(<(Lens<Position>, &Transform)>::get_access().is_err());
+    }
+
+    #[test]
+    fn map_query() {
+        struct AsU64;
+        impl Mapper for AsU64 {
+            type Param = &'static u32;
+            type Output = u64;
+            fn map(item: &u32) -> u64 {
+                *item as u64
+            }
+        }
+        struct Total;
+        impl Mapper for Total {
+            type Param = (&'static u32, Maybe<&'static u64>);
+            type Output = u64;
+            fn map((a, b): (&u32, Option<&u64>)) -> u64 {
+                *a as u64 + b.copied().unwrap_or(0)
+            }
+        }
+
+        let mut world = World::new();
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).insert(20_u64).id();
+        world.spawn().insert(30_u64);
+
+        let mut q = world.query::<(Entity, Map<AsU64>)>().unwrap();
+        let mapped = q.iter_mut().collect::<Vec<_>>();
+        assert_eq!(mapped, [(e1, 1_u64), (e2, 2)]);
+        let mut q = world.query::<Map<Total>>().unwrap();
+        assert_eq!(q.iter_mut().collect::<Vec<_>>(), [1, 22]);
+
+        assert!(<(Map<AsU64>, &mut u32)>::get_access().is_err());
+    }
+
+    #[test]
+    fn sparse_query() {
+        let mut world = World::new();
+        world.register_component::<u64>(StorageKind::Sparse);
+        let e1 = world.spawn().insert(1_u32).insert(10_u64).id();
+        let e2 = world.spawn().insert(2_u32).id();
+        let e3 = world.spawn().insert(3_u32).insert(30_u64).id();
+        world.spawn().insert(40_u64);
+
+        let mut q = world.query::<(Entity, &mut u32, Sparse<&u64>)>().unwrap();
+        let mut visited = vec![];
+        for (entity, table, sparse) in &mut q {
+            *table += 1;
+            visited.push((entity, *table, sparse.copied()));
+        }
+        assert_eq!(
+            visited,
+            [(e1, 2, Some(10)), (e2, 3, None), (e3, 4, Some(30))]
+        );
+        drop(q);
+
+        assert!(world.query::<&u64>().is_err());
+        assert!(world.query::<&mut u64>().is_err());
+        assert!(world.query::<Sparse<&u32>>().is_err());
+        let _set = world.sparse_set::<u64>().unwrap();
+        assert!(<(&u32, Sparse<&u64>)>::get_access().is_ok());
+        assert!(world.query::<Sparse<&u64>>().is_ok());
+    }
+
     #[test]
     fn simple_query() {
         let mut world = World::new();
@@ -605,6 +1391,83 @@ mod static_tests {
         )
     }
 
+    #[test]
+    fn maybe_mut_query() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(10_u32).insert(12_u64).id();
+        let e2 = world.spawn().insert(13_u64).id();
+        let e3 = world.spawn().insert(11_u32).id();
+
+        let mut q = world.query::<(Entity, Maybe<&mut u32>)>().unwrap();
+        let mut returned = vec![];
+        for (entity, value) in &mut q {
+            if let Some(value) = value {
+                *value += 1;
+                returned.push((entity, Some(*value)));
+            } else {
+                returned.push((entity, None));
+            }
+        }
+        drop(q);
+        returned.sort();
+        assert_eq!(returned, [(e1, Some(11)), (e2, None), (e3, Some(12))]);
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 11);
+        assert_eq!(*world.get_component::<u32>(e3).unwrap(), 12);
+        assert_eq!(world.has_component::<u32>(e2), Some(false));
+    }
+
+    #[test]
+    fn maybe_mut_with_entity_and_ref() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(1_u64).id();
+        let e2 = world.spawn().insert(10_u32).insert(2_u64).id();
+        let e3 = world.spawn().insert(3_u64).insert(true).id();
+        let _e4 = world.spawn().insert(11_u32).id();
+        let e5 = world.spawn().insert(12_u32).insert(5_u64).insert(true).id();
+        let e6 = world.spawn().insert(6_u64).id();
+
+        let expected = vec![
+            (e1, None, 1),
+            (e2, Some(11), 2),
+            (e3, None, 3),
+            (e5, Some(13), 5),
+            (e6, None, 6),
+        ];
+        let mut q = world.query::<(Entity, Maybe<&mut u32>, &u64)>().unwrap();
+        let mut returned = q
+            .iter_mut()
+            .map(|(entity, value, other)| {
+                let value = value.map(|value| {
+                    *value += 1;
+                    *value
+                });
+                (entity, value, *other)
+            })
+            .collect::<Vec<_>>();
+        returned.sort();
+        assert_eq!(returned, expected);
+        drop(q);
+
+        // same again with `Maybe` first, and reading the already incremented values back
+        let mut q = world.query::<(Maybe<&mut u32>, Entity, &u64)>().unwrap();
+        let mut returned = q
+            .iter_mut()
+            .map(|(value, entity, other)| (entity, value.map(|value| *value), *other))
+            .collect::<Vec<_>>();
+        returned.sort();
+        assert_eq!(returned, expected);
+    }
+
+    #[test]
+    fn maybe_mut_on_uncreated_column() {
+        let mut world = World::new();
+        let e1 = world.spawn().id();
+        let mut q = world.query::<(Entity, Maybe<&mut u32>)>().unwrap();
+        let mut iter = q.iter_mut();
+        assert_eq!(iter.next(), Some((e1, None)));
+        assert_eq!(iter.next(), None);
+    }
+
     #[test]
     fn query_with_despawned() {
         let mut world = World::new();
@@ -630,6 +1493,153 @@ mod static_tests {
         let _q3 = world.query::<&u32>().unwrap();
     }
 
+    #[test]
+    fn query_uncreated_column() {
+        let mut world = World::new();
+        world.spawn().insert(10_u32);
+
+        assert_eq!(world.query::<&u64>().unwrap().iter_mut().next(), None);
+        assert_eq!(world.query::<&mut u64>().unwrap().iter_mut().next(), None);
+        assert_eq!(
+            world.query::<(&u64, &u128)>().unwrap().iter_mut().next(),
+            None
+        );
+        assert_eq!(
+            world.query::<(&u32, &u64)>().unwrap().iter_mut().next(),
+            None
+        );
+        assert_eq!(
+            world
+                .query::<(Entity, &mut u32, &u64)>()
+                .unwrap()
+                .iter_mut()
+                .next(),
+            None
+        );
+        assert_eq!(world.query::<(&u64, &u32)>().unwrap().iter_mut().count(), 0);
+        assert_eq!(
+            world
+                .query::<(&u32, &u64)>()
+                .unwrap()
+                .iter_by_archetype_mut()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn any_of_query() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u64).id();
+        let e3 = world.spawn().insert(3_u32).insert(3_u64).id();
+        world.spawn().insert(true);
+        world.spawn();
+
+        let mut q = world
+            .query::<(Entity, AnyOf<(&mut u32, &u64, &u128)>)>()
+            .unwrap();
+        let mut returned = q
+            .iter_mut()
+            .map(|(e, (a, b, c))| (e, a.map(|a| *a), b.copied(), c.copied()))
+            .collect::<Vec<_>>();
+        returned.sort();
+        assert_eq!(
+            returned,
+            [
+                (e1, Some(1), None, None),
+                (e2, None, Some(2), None),
+                (e3, Some(3), Some(3), None)
+            ]
+        );
+        assert!(<AnyOf<(&mut u32, &u32)>>::get_access().is_err());
+    }
+
+    #[test]
+    fn get_disjoint_mut() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).insert(2_u64).id();
+        let e3 = world.spawn().insert(3_u32).id();
+        let e4 = world.spawn().insert(4_u64).id();
+        let dead = world.spawn().insert(5_u32).id();
+        world.despawn(dead);
+
+        let mut q = world.query::<&mut u32>().unwrap();
+        let [a, b, c] = q.get_disjoint_mut([e3, e2, e1]);
+        let (a, b, c) = (a.unwrap(), b.unwrap(), c.unwrap());
+        std::mem::swap(a, b);
+        *c += 10;
+        assert_eq!(q.get_disjoint_mut([e4, dead]), [None, None]);
+        drop(q);
+
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 11);
+        assert_eq!(*world.get_component::<u32>(e2).unwrap(), 3);
+        assert_eq!(*world.get_component::<u32>(e3).unwrap(), 2);
+
+        assert_eq!(
+            world.query::<&mut u128>().unwrap().get_disjoint_mut([e1]),
+            [None]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "passed to `get_disjoint_mut` more than once")]
+    fn get_disjoint_mut_duplicate() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).id();
+        world
+            .query::<&mut u32>()
+            .unwrap()
+            .get_disjoint_mut([e1, e2, e1]);
+    }
+
+    #[test]
+    fn par_chunks() {
+        let mut world = World::new();
+        for n in 0..1000_u64 {
+            let e = world.spawn().insert(n).id();
+            if n % 3 == 0 {
+                world.insert_component(e, n as u32);
+            }
+        }
+        let serial = world
+            .query::<&u64>()
+            .unwrap()
+            .iter_mut()
+            .map(|n| *n * 2)
+            .sum::<u64>();
+
+        let mut q = world.query::<&mut u64>().unwrap();
+        let chunks = q.par_chunks(64).collect::<Vec<_>>();
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.len() <= 64 && !chunk.is_empty()));
+        let parallel = std::thread::scope(|scope| {
+            let handles = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk.iter_mut().for_each(|n| *n *= 2);
+                        chunk.iter().sum::<u64>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum::<u64>()
+        });
+        assert_eq!(parallel, serial);
+        assert_eq!(q.iter_mut().map(|n| *n).sum::<u64>(), serial);
+
+        assert_eq!(
+            world.query::<&mut u128>().unwrap().par_chunks(64).count(),
+            0
+        );
+    }
+
     #[test]
     fn maybe_on_uncreated_column() {
         let mut world = World::new();
@@ -640,6 +1650,87 @@ mod static_tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn query_by_archetype() {
+        let mut world = World::new();
+        world.spawn().insert(1_u32);
+        world.spawn().insert(2_u32).insert(10_u64);
+        world.spawn().insert(3_u32).insert(11_u64);
+        world.spawn().insert(12_u64);
+
+        let mut q = world.query::<&mut u32>().unwrap();
+        let mut sums = vec![];
+        for archetype in q.iter_by_archetype_mut() {
+            let mut sum = 0;
+            for n in archetype {
+                *n *= 2;
+                sum += *n;
+            }
+            sums.push(sum);
+        }
+        assert_eq!(sums, [2, 10]);
+        drop(q);
+
+        let mut q = world.query::<&u32>().unwrap();
+        let counts = q
+            .iter_by_archetype_mut()
+            .map(|archetype| archetype.count())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [1, 2]);
+    }
+
+    #[test]
+    fn query_count() {
+        let mut world = World::new();
+        for n in 0..10_u32 {
+            let e = world.spawn().insert(n).id();
+            if n % 2 == 0 {
+                world.insert_component(e, n as u64);
+            }
+        }
+        world.spawn().insert(10_u64);
+
+        let mut q = world.query::<&mut u32>().unwrap();
+        let len = q.iter_mut().collect::<Vec<_>>().len();
+        assert_eq!(q.iter_mut().count(), len);
+        assert_eq!(len, 10);
+
+        let mut iter = q.iter_mut();
+        iter.next();
+        iter.next();
+        assert_eq!(iter.count(), 8);
+        drop(q);
+
+        let mut q = world.query::<(&u32, &mut u64)>().unwrap();
+        assert_eq!(q.iter_mut().count(), 5);
+        drop(q);
+
+        let mut q = world.query::<&u128>().unwrap();
+        assert_eq!(q.iter_mut().count(), 0);
+    }
+
+    #[test]
+    fn peek_query() {
+        let mut world = World::new();
+        world.spawn().insert(1_u32);
+        world.spawn().insert(2_u32).insert(10_u64);
+
+        {
+            let mut q = world.query::<&u32>().unwrap();
+            let mut iter = q.iter_mut().peekable();
+            assert_eq!(iter.peek(), Some(&&1));
+            assert_eq!(iter.next(), Some(&1));
+            assert_eq!(iter.peek(), Some(&&2));
+            assert_eq!(iter.next(), Some(&2));
+            assert_eq!(iter.peek(), None);
+        }
+
+        let mut q = world.query::<&mut u32>().unwrap();
+        let mut iter = q.iter_mut().peekable();
+        **iter.peek_mut().unwrap() += 10;
+        assert_eq!(iter.next(), Some(&mut 11));
+    }
+
     #[test]
     fn complex_maybe_query() {
         let mut world = World::new();
@@ -688,6 +1779,80 @@ mod dynamic_tests {
         assert_eq!(q_iter.next_dynamic(), None);
     }
 
+    #[test]
+    fn query_dynamic() {
+        let mut world = World::new();
+        let u32_id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let u64_id = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        for e in [e1, e2] {
+            world.insert_component_dynamic(e, u32_id, |ptr| unsafe { *(ptr.1 as *mut u32) = 10 });
+        }
+        world.insert_component_dynamic(e2, u64_id, |ptr| unsafe { *(ptr.1 as *mut u64) = 12 });
+
+        let mut q = world
+            .query_dynamic(&[
+                DynQueryParam::new_mut(u32_id),
+                DynQueryParam::new_ref(u64_id),
+            ])
+            .unwrap();
+        let mut iter = q.iter_mut();
+        let (_, ptrs) = iter.next_dynamic().unwrap();
+        unsafe {
+            assert_eq!(*(ptrs[0] as *mut u32), 10);
+            assert_eq!(*(ptrs[1] as *mut u64), 12);
+        }
+        assert!(iter.next_dynamic().is_none());
+        drop(iter);
+        drop(q);
+
+        assert!(world
+            .query_dynamic(&[
+                DynQueryParam::new_mut(u32_id),
+                DynQueryParam::new_mut(u32_id)
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn conflicting_dyn_params() {
+        let mut world = World::new();
+        let u32_id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e = world.spawn().id();
+        world.insert_component_dynamic(e, u32_id, |ptr| unsafe { *(ptr.1 as *mut u32) = 10 });
+
+        let mut q = world.query::<()>().unwrap();
+        q.try_add_dyn_param(DynQueryParam::new_mut(u32_id)).unwrap();
+        assert!(q.try_add_dyn_param(DynQueryParam::new_mut(u32_id)).is_err());
+        assert!(q.try_add_dyn_param(DynQueryParam::new_ref(u32_id)).is_err());
+        assert_eq!(q.iter_mut().count(), 1);
+
+        // also caught when the static part of the query has no locks, including after
+        // a param has already been rejected
+        let mut q = world.query::<&u64>().unwrap();
+        q.try_add_dyn_param(DynQueryParam::new_mut(u32_id)).unwrap();
+        assert!(q.try_add_dyn_param(DynQueryParam::new_mut(u32_id)).is_err());
+        assert!(q.try_add_dyn_param(DynQueryParam::new_mut(u32_id)).is_err());
+        assert!(q.try_add_dyn_param(DynQueryParam::new_ref(u32_id)).is_err());
+    }
+
+    #[test]
+    fn failed_dyn_param_lock_not_recorded() {
+        let mut world = World::new();
+        let u32_id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e = world.spawn().id();
+        world.insert_component_dynamic(e, u32_id, |ptr| unsafe { *(ptr.1 as *mut u32) = 10 });
+
+        let mut locked = world.query::<()>().unwrap();
+        locked.add_dyn_param(DynQueryParam::new_mut(u32_id));
+        let mut q = world.query::<()>().unwrap();
+        assert!(q.try_add_dyn_param(DynQueryParam::new_ref(u32_id)).is_err());
+        drop(locked);
+        q.try_add_dyn_param(DynQueryParam::new_mut(u32_id)).unwrap();
+        assert_eq!(q.iter_mut().count(), 1);
+    }
+
     #[test]
     fn uncreated_column() {
         let mut world = World::new();
@@ -1,4 +1,4 @@
-use crate::{Commands, CommandsWithEntity, World};
+use crate::{Commands, CommandsWithEntity, EntityBuilder, World};
 
 pub trait Scope {
     fn scope(&self, f: impl FnOnce(&Self)) -> &Self {
@@ -15,3 +15,4 @@ pub trait Scope {
 impl Scope for Commands<'_> {}
 impl Scope for CommandsWithEntity<'_, '_> {}
 impl Scope for World {}
+impl Scope for EntityBuilder<'_> {}
@@ -0,0 +1,195 @@
+use std::any::Any;
+
+use crate::Entity;
+
+/// Stores components densely alongside an index from entity slot to position in the dense
+/// array. Inserting and removing are O(1) and never move the entity between archetypes,
+/// unlike archetype columns, at the cost of a lookup per entity when joined with them.
+pub struct SparseSet<T> {
+    /// Position in `dense` of the component for each entity slot
+    sparse: Vec<Option<usize>>,
+    dense: Vec<(Entity, T)>,
+}
+
+impl<T> SparseSet<T> {
+    pub fn new() -> Self {
+        Self {
+            sparse: Vec::new(),
+            dense: Vec::new(),
+        }
+    }
+
+    fn dense_idx(&self, entity: Entity) -> Option<usize> {
+        let idx = (*self.sparse.get(entity.index())?)?;
+        (self.dense[idx].0 == entity).then(|| idx)
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.dense_idx(entity).is_some()
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        let idx = self.dense_idx(entity)?;
+        Some(&self.dense[idx].1)
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        let idx = self.dense_idx(entity)?;
+        Some(&mut self.dense[idx].1)
+    }
+
+    /// Returns the old component if `entity` already had one
+    pub fn insert(&mut self, entity: Entity, component: T) -> Option<T> {
+        if let Some(idx) = self.dense_idx(entity) {
+            return Some(std::mem::replace(&mut self.dense[idx].1, component));
+        }
+
+        // the slot may still hold a component of a previous generation of this entity
+        if let Some(Some(stale)) = self.sparse.get(entity.index()) {
+            self.swap_remove_dense(*stale);
+        }
+        if self.sparse.len() <= entity.index() {
+            self.sparse.resize(entity.index() + 1, None);
+        }
+        self.sparse[entity.index()] = Some(self.dense.len());
+        self.dense.push((entity, component));
+        None
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        let idx = self.dense_idx(entity)?;
+        Some(self.swap_remove_dense(idx))
+    }
+
+    fn swap_remove_dense(&mut self, idx: usize) -> T {
+        let (entity, component) = self.dense.swap_remove(idx);
+        self.sparse[entity.index()] = None;
+        if let Some((moved, _)) = self.dense.get(idx) {
+            self.sparse[moved.index()] = Some(idx);
+        }
+        component
+    }
+
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    /// Iterates in dense order which is not the order entities were spawned in
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> + '_ {
+        self.dense
+            .iter()
+            .map(|(entity, component)| (*entity, component))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> + '_ {
+        self.dense
+            .iter_mut()
+            .map(|(entity, component)| (*entity, component))
+    }
+
+    pub fn clear(&mut self) {
+        self.sparse.clear();
+        self.dense.clear();
+    }
+}
+
+impl<T> Default for SparseSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lets `World` despawn entities from sparse sets without knowing their component type
+pub(crate) trait ErasedSparseSet {
+    fn contains_entity(&self, entity: Entity) -> bool;
+    fn remove_entity(&mut self, entity: Entity);
+    fn for_each_entity(&self, f: &mut dyn FnMut(Entity));
+    fn clear(&mut self);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: 'static> ErasedSparseSet for SparseSet<T> {
+    fn contains_entity(&self, entity: Entity) -> bool {
+        self.contains(entity)
+    }
+
+    fn remove_entity(&mut self, entity: Entity) {
+        self.remove(entity);
+    }
+
+    fn for_each_entity(&self, f: &mut dyn FnMut(Entity)) {
+        for &(entity, _) in &self.dense {
+            f(entity);
+        }
+    }
+
+    fn clear(&mut self) {
+        SparseSet::clear(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl dyn ErasedSparseSet {
+    pub(crate) fn downcast_ref<T: 'static>(&self) -> &SparseSet<T> {
+        self.as_any().downcast_ref().unwrap()
+    }
+
+    pub(crate) fn downcast_mut<T: 'static>(&mut self) -> &mut SparseSet<T> {
+        self.as_any_mut().downcast_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        let mut set = SparseSet::new();
+        assert_eq!(set.insert(Entity::new(3, 0), 30_u32), None);
+        assert_eq!(set.insert(Entity::new(0, 0), 0), None);
+        assert_eq!(set.insert(Entity::new(7, 0), 70), None);
+        assert_eq!(set.insert(Entity::new(0, 0), 1), Some(0));
+        assert_eq!(set.len(), 3);
+
+        // removing from the front moves the last element into its place
+        assert_eq!(set.remove(Entity::new(3, 0)), Some(30));
+        assert_eq!(set.remove(Entity::new(3, 0)), None);
+        assert_eq!(set.get(Entity::new(7, 0)), Some(&70));
+        assert_eq!(set.get(Entity::new(0, 0)), Some(&1));
+        *set.get_mut(Entity::new(7, 0)).unwrap() += 1;
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [(Entity::new(7, 0), &71), (Entity::new(0, 0), &1)]
+        );
+
+        set.clear();
+        assert!(set.is_empty());
+        assert!(!set.contains(Entity::new(7, 0)));
+    }
+
+    #[test]
+    fn stale_generation() {
+        let mut set = SparseSet::new();
+        set.insert(Entity::new(0, 0), 1_u32);
+        set.insert(Entity::new(1, 0), 2);
+        assert_eq!(set.get(Entity::new(0, 1)), None);
+        assert_eq!(set.insert(Entity::new(0, 1), 3), None);
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.get(Entity::new(0, 0)), None);
+        assert_eq!(set.get(Entity::new(0, 1)), Some(&3));
+        assert_eq!(set.get(Entity::new(1, 0)), Some(&2));
+    }
+}
@@ -1,19 +1,42 @@
-use crate::{errors, query::QueryParam, CommandBuffer, Commands, Query, World};
+use crate::{errors, query::QueryParam, CommandBuffer, Commands, EcsTypeId, Query, World};
 use std::{any::TypeId, collections::HashSet, marker::PhantomData};
 
+/// Tracks which components are accessed. Static components are tracked by `TypeId`
+/// and dynamic components by `EcsTypeId`, accesses are not checked across the two.
+/// An exclusive access is the access of a system taking `&mut World` and conflicts with
+/// every other access, even an empty one as a system with an empty access may still read
+/// the world through `&World`.
+#[derive(Clone)]
 pub struct Access {
+    exclusive: bool,
     read: HashSet<TypeId>,
     write: HashSet<TypeId>,
+    read_dynamic: HashSet<EcsTypeId>,
+    write_dynamic: HashSet<EcsTypeId>,
 }
 
 impl Access {
     pub fn new() -> Self {
         Self {
+            exclusive: false,
             read: HashSet::new(),
             write: HashSet::new(),
+            read_dynamic: HashSet::new(),
+            write_dynamic: HashSet::new(),
         }
     }
 
+    pub fn exclusive() -> Self {
+        Self {
+            exclusive: true,
+            ..Self::new()
+        }
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+
     pub fn insert_write(mut self, id: TypeId) -> Result<Self, ()> {
         if self.write.contains(&id) || self.read.contains(&id) {
             return Err(());
@@ -30,8 +53,27 @@ impl Access {
         Ok(self)
     }
 
+    pub fn insert_write_dynamic(mut self, id: EcsTypeId) -> Result<Self, ()> {
+        if self.write_dynamic.contains(&id) || self.read_dynamic.contains(&id) {
+            return Err(());
+        }
+        self.write_dynamic.insert(id);
+        Ok(self)
+    }
+
+    pub fn insert_read_dynamic(mut self, id: EcsTypeId) -> Result<Self, ()> {
+        if self.write_dynamic.contains(&id) {
+            return Err(());
+        }
+        self.read_dynamic.insert(id);
+        Ok(self)
+    }
+
     pub fn join_with(mut self, other: Result<Access, ()>) -> Result<Self, ()> {
         let other = other?;
+        if self.exclusive || other.exclusive {
+            return Err(());
+        }
         self.read.extend(other.read.iter().copied());
         if self.write.intersection(&other.write).next().is_some() {
             return Err(());
@@ -40,12 +82,51 @@ impl Access {
         if self.read.intersection(&self.write).next().is_some() {
             return Err(());
         }
+
+        self.read_dynamic.extend(other.read_dynamic.iter().copied());
+        if self
+            .write_dynamic
+            .intersection(&other.write_dynamic)
+            .next()
+            .is_some()
+        {
+            return Err(());
+        }
+        self.write_dynamic
+            .extend(other.write_dynamic.iter().copied());
+        if self
+            .read_dynamic
+            .intersection(&self.write_dynamic)
+            .next()
+            .is_some()
+        {
+            return Err(());
+        }
         Ok(self)
     }
 
+    /// Whether `self` and `other` could be joined without conflicting, without
+    /// actually merging them.
+    pub fn is_compatible_with(&self, other: &Access) -> bool {
+        !self.exclusive
+            && !other.exclusive
+            && self.write.is_disjoint(&other.write)
+            && self.write.is_disjoint(&other.read)
+            && self.read.is_disjoint(&other.write)
+            && self.write_dynamic.is_disjoint(&other.write_dynamic)
+            && self.write_dynamic.is_disjoint(&other.read_dynamic)
+            && self.read_dynamic.is_disjoint(&other.write_dynamic)
+    }
+
+    /// Joins every access in `accesses`, starting from the first so that a single
+    /// exclusive access is not joined with an empty one.
     pub fn from_array<const N: usize>(accesses: [Result<Access, ()>; N]) -> Result<Self, ()> {
-        let mut output = Access::new();
-        for access in accesses.into_iter() {
+        let mut accesses = accesses.into_iter();
+        let mut output = match accesses.next() {
+            Some(access) => access?,
+            None => return Ok(Access::new()),
+        };
+        for access in accesses {
             output = output.join_with(access)?;
         }
         Ok(output)
@@ -128,6 +209,46 @@ impl<'a> SystemParam for Commands<'a> {
     }
 }
 
+/// State private to a system that persists across runs of the same system, initialized
+/// with `T::default()`. `access_scope` creates the system each time it is called so a
+/// `Local` only persists when the same `Box<dyn System>` is run repeatedly.
+pub struct Local<'a, T>(&'a mut T);
+
+impl<'a, T> std::ops::Deref for Local<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.0
+    }
+}
+
+impl<'a, T> std::ops::DerefMut for Local<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0
+    }
+}
+
+impl<'a, T: Default + 'static> SystemParam for Local<'a, T> {
+    type SelfCtor<'b> = Local<'b, T>;
+    type SystemParamState = T;
+
+    fn from_world<'b>(
+        _: &'b World,
+        state: &'b mut Self::SystemParamState,
+    ) -> Result<Self::SelfCtor<'b>, errors::WorldBorrowError> {
+        Ok(Local(state))
+    }
+
+    fn get_access() -> Result<Access, ()> {
+        Ok(Access::new())
+    }
+
+    fn new_state() -> Self::SystemParamState {
+        T::default()
+    }
+
+    fn system_finish_event(_: &mut Self::SystemParamState, _: &mut World) {}
+}
+
 macro_rules! system_param_tuple_impl {
     ($($T:ident)+) => {
         impl<$($T: SystemParam),+> SystemParam for ($($T,)+) {
@@ -173,6 +294,38 @@ pub trait System {
     type Out;
     fn run(&mut self, world: &mut World) -> Self::Out;
     fn get_access(&self) -> Result<Access, ()>;
+    /// A human readable name for diagnostics, for systems built from functions this
+    /// is the function's type name.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+struct ExclusiveSystem<Func>(Func);
+
+impl<Out, Func: FnMut(&mut World) -> Out> System for ExclusiveSystem<Func> {
+    type Out = Out;
+
+    fn run(&mut self, world: &mut World) -> Out {
+        (self.0)(world)
+    }
+
+    fn get_access(&self) -> Result<Access, ()> {
+        Ok(Access::exclusive())
+    }
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Func>()
+    }
+}
+
+/// Creates a system with mutable access to the whole `World`. `&mut World` can't be a
+/// `SystemParam` as params are created from a shared borrow of the world, so exclusive
+/// systems can't take any other params. The system's access is `Access::exclusive`.
+pub fn exclusive_system<'a, Out>(
+    func: impl FnMut(&mut World) -> Out + 'a,
+) -> Box<dyn System<Out = Out> + 'a> {
+    Box::new(ExclusiveSystem(func))
 }
 
 struct FunctionSystem<State, In, Func>(State, Func, PhantomData<fn(In)>)
@@ -205,6 +358,10 @@ macro_rules! system_impl {
                 fn get_access(&self) -> Result<Access, ()> {
                     Access::from_array([$($T::get_access()),+])
                 }
+
+                fn name(&self) -> &str {
+                    std::any::type_name::<Func>()
+                }
             }
 
         impl<Out, Func, $($T: SystemParam + 'static,)+> ToSystem<($($T,)+), Out> for Func
@@ -253,6 +410,114 @@ mod tests {
         world.access_scope(query);
     }
 
+    #[test]
+    fn access_compatibility() {
+        let read_u32 = Access::new().insert_read(TypeId::of::<u32>()).unwrap();
+        let read_u32_2 = Access::new().insert_read(TypeId::of::<u32>()).unwrap();
+        let write_u32 = Access::new().insert_write(TypeId::of::<u32>()).unwrap();
+        let write_u64 = Access::new().insert_write(TypeId::of::<u64>()).unwrap();
+
+        assert!(read_u32.is_compatible_with(&read_u32_2));
+        assert!(!read_u32.is_compatible_with(&write_u32));
+        assert!(!write_u32.is_compatible_with(&read_u32));
+        assert!(!write_u32.is_compatible_with(&write_u32));
+        assert!(write_u32.is_compatible_with(&write_u64));
+        assert!(read_u32.is_compatible_with(&write_u64));
+    }
+
+    #[test]
+    fn dynamic_access() {
+        let mut world = World::new();
+        let id = world.new_dynamic_ecs_type_id(std::alloc::Layout::new::<u32>());
+        let other_id = world.new_dynamic_ecs_type_id(std::alloc::Layout::new::<u32>());
+
+        let write = || crate::DynQueryParam::new_mut(id).get_access();
+        let read = || crate::DynQueryParam::new_ref(id).get_access();
+        assert!(Access::from_array([write(), write()]).is_err());
+        assert!(Access::from_array([read(), write()]).is_err());
+        assert!(Access::from_array([read(), read()]).is_ok());
+        assert!(Access::from_array([
+            write(),
+            crate::DynQueryParam::new_mut(other_id).get_access()
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn try_access_scope() {
+        fn conflicting(_: Query<&mut u32>, _: Query<(&u64, &u32)>) {}
+        fn fine(mut q: Query<&mut u32>, _: Query<&u64>) -> u32 {
+            q.iter_mut().map(|value| *value).sum()
+        }
+        let mut world = World::new();
+        world.spawn().insert(10_u32).insert(12_u64);
+        assert!(world.try_access_scope(conflicting).is_err());
+        assert_eq!(world.try_access_scope(fine).unwrap(), 10);
+        let err = world.try_access_scope(conflicting).unwrap_err();
+        assert_eq!(err.0, conflicting.system().name());
+    }
+
+    #[test]
+    fn exclusive_system() {
+        fn read(_: Query<&u32>) {}
+        fn shared(_: &World) {}
+        fn spawn(world: &mut World) {
+            world.spawn().insert(10_u32);
+        }
+        let mut world = World::new();
+        let mut systems = [
+            read.system(),
+            shared.system(),
+            super::exclusive_system(spawn),
+        ];
+
+        let accesses = systems
+            .iter()
+            .map(|system| system.get_access().unwrap())
+            .collect::<Vec<_>>();
+        assert!(accesses[0].is_compatible_with(&accesses[1]));
+        assert!(!accesses[2].is_compatible_with(&accesses[0]));
+        assert!(!accesses[1].is_compatible_with(&accesses[2]));
+        assert!(!accesses[2].is_compatible_with(&Access::new()));
+        assert!(!Access::new().is_compatible_with(&accesses[2]));
+        assert!(Access::new().join_with(Ok(Access::exclusive())).is_err());
+        assert!(Access::exclusive().join_with(Ok(Access::new())).is_err());
+        assert!(Access::from_array([Ok(Access::exclusive())]).is_ok());
+        assert!(Access::from_array([Ok(Access::exclusive()), read.system().get_access()]).is_err());
+
+        systems[2].run(&mut world);
+        assert_eq!(world.query::<&u32>().unwrap().iter_mut().count(), 1);
+    }
+
+    #[test]
+    fn system_name() {
+        fn move_players(_: Query<&mut u32>) {}
+        fn spawn_enemies(_: &mut World) {}
+        assert!(move_players.system().name().contains("move_players"));
+        assert!(super::exclusive_system(spawn_enemies)
+            .name()
+            .contains("spawn_enemies"));
+    }
+
+    #[test]
+    fn local() {
+        fn counter(mut count: Local<u32>, mut other: Local<u64>, _: Query<&u32>) -> u32 {
+            *count += 1;
+            *other += 2;
+            *count
+        }
+        let mut world = World::new();
+        let mut sys = counter.system();
+        assert_eq!(sys.run(&mut world), 1);
+        assert_eq!(sys.run(&mut world), 2);
+        assert_eq!(sys.run(&mut world), 3);
+
+        let mut other_sys = counter.system();
+        assert_eq!(other_sys.run(&mut world), 1);
+        assert_eq!(sys.run(&mut world), 4);
+        assert_eq!(world.access_scope(counter), 1);
+    }
+
     #[should_panic]
     #[test]
     fn conflict() {
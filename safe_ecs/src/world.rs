@@ -3,19 +3,63 @@ use std::{
     cell::{self, RefCell},
     collections::HashMap,
     mem::MaybeUninit,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
 use crate::{
     dynamic_storage::ErasedBytesVec,
     entities::{Entities, Entity, EntityMeta},
-    errors, query, LtPtr, LtPtrMut, LtPtrOwn, LtPtrWriteOnly,
+    errors, query,
+    sparse_set::{ErasedSparseSet, SparseSet},
+    LtPtr, LtPtrMut, LtPtrOwn, LtPtrWriteOnly,
 };
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
 pub struct EcsTypeId(usize);
 
+/// How a component type is stored, see `World::register_component`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StorageKind {
+    /// In per-archetype columns, the default. Iterating is fast but adding or removing
+    /// the component moves the entity to another archetype.
+    Table,
+    /// In a `SparseSet`, adding or removing the component never moves the entity.
+    Sparse,
+}
+
 pub trait Component: 'static {}
 
+/// A set of components that can be inserted into an entity at once
+pub trait Bundle: 'static {
+    fn insert_into(self, world: &mut World, entity: Entity);
+}
+
+macro_rules! bundle_tuple_impl {
+    ($($T:ident)+) => {
+        impl<$($T: Component),+> Bundle for ($($T,)+) {
+            #[allow(non_snake_case)]
+            fn insert_into(self, world: &mut World, entity: Entity) {
+                let ($($T,)+) = self;
+                $(world.insert_component(entity, $T);)+
+            }
+        }
+    };
+}
+
+bundle_tuple_impl!(A B C D E F G H);
+bundle_tuple_impl!(A B C D E F G);
+bundle_tuple_impl!(A B C D E F);
+bundle_tuple_impl!(A B C D E);
+bundle_tuple_impl!(A B C D);
+bundle_tuple_impl!(A B C);
+bundle_tuple_impl!(A B);
+bundle_tuple_impl!(A);
+
+/// A column of components for one archetype. The element at row `i` always belongs to
+/// the `i`th entity of `Archetype::entities`, removing an entity swap-removes its row so
+/// the last element moves into the gap, exactly like `Vec::swap_remove`.
+/// `World::debug_entity_row` can be used to check a storage agrees with the world about
+/// which row an entity is in.
 pub trait Storage: 'static {
     fn as_typed_storage(&self) -> Option<&dyn TypedStorage>;
     fn as_typed_storage_mut(&mut self) -> Option<&mut dyn TypedStorage>;
@@ -27,9 +71,16 @@ pub trait Storage: 'static {
 
     fn swap_remove_move_to(&mut self, other: &mut Box<dyn Storage>, idx: usize);
     fn swap_remove_and_drop(&mut self, idx: usize);
+    /// Reorders elements so that the element at `permutation[i]` ends up at `i`
+    fn apply_permutation(&mut self, permutation: &[usize]);
 
     fn get_element_ptr(&self, idx: usize) -> LtPtr<'_>;
     fn get_element_ptr_mut(&mut self, idx: usize) -> LtPtrMut<'_>;
+
+    fn num_elements(&self) -> usize;
+    /// Shortens the storage to `new_len` elements, dropping the removed elements. Does
+    /// nothing if the storage is already shorter.
+    fn truncate(&mut self, new_len: usize);
 }
 
 pub trait TypedStorage: 'static {
@@ -71,6 +122,14 @@ impl<T: Component> Storage for Vec<T> {
         self.swap_remove(idx);
     }
 
+    fn apply_permutation(&mut self, permutation: &[usize]) {
+        let mut old = std::mem::take(self)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        self.extend(permutation.iter().map(|&idx| old[idx].take().unwrap()));
+    }
+
     fn get_element_ptr(&self, idx: usize) -> LtPtr<'_> {
         let ptr = &self[idx] as *const T as *const MaybeUninit<u8>;
         let ptr = std::ptr::slice_from_raw_parts(ptr, std::mem::size_of::<T>());
@@ -82,6 +141,14 @@ impl<T: Component> Storage for Vec<T> {
         let ptr = std::ptr::slice_from_raw_parts_mut(ptr, std::mem::size_of::<T>());
         LtPtrMut(Default::default(), ptr)
     }
+
+    fn num_elements(&self) -> usize {
+        self.len()
+    }
+
+    fn truncate(&mut self, new_len: usize) {
+        Vec::truncate(self, new_len);
+    }
 }
 impl<T: Component> TypedStorage for Vec<T> {
     fn as_any(&self) -> &dyn Any {
@@ -137,6 +204,10 @@ impl Storage for Box<dyn ErasedBytesVec> {
         (&mut **self).swap_remove(idx);
     }
 
+    fn apply_permutation(&mut self, permutation: &[usize]) {
+        (&mut **self).apply_permutation(permutation);
+    }
+
     fn get_element_ptr(&self, idx: usize) -> LtPtr<'_> {
         (&**self).get_element_ptr(idx)
     }
@@ -144,39 +215,122 @@ impl Storage for Box<dyn ErasedBytesVec> {
     fn get_element_ptr_mut(&mut self, idx: usize) -> LtPtrMut<'_> {
         (&mut **self).get_element_ptr_mut(idx)
     }
+
+    fn num_elements(&self) -> usize {
+        (&**self).num_elements()
+    }
+
+    fn truncate(&mut self, new_len: usize) {
+        (&mut **self).truncate(new_len);
+    }
 }
 
 #[derive(Debug)]
 pub struct Archetype {
     pub(crate) entities: Vec<Entity>,
+    /// The index of each entity in `entities`, must be kept in sync with it
+    entity_indices: HashMap<Entity, usize>,
     pub(crate) column_indices: HashMap<EcsTypeId, usize>,
 }
 
 impl Archetype {
-    fn get_entity_idx(&self, entity: Entity) -> Option<usize> {
-        self.entities.iter().position(|e| *e == entity)
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    pub fn contains_component(&self, id: EcsTypeId) -> bool {
+        self.column_indices.contains_key(&id)
+    }
+
+    pub fn component_count(&self) -> usize {
+        self.column_indices.len()
+    }
+
+    pub(crate) fn get_entity_idx(&self, entity: Entity) -> Option<usize> {
+        self.entity_indices.get(&entity).copied()
+    }
+
+    fn new(entities: Vec<Entity>, column_indices: HashMap<EcsTypeId, usize>) -> Self {
+        let mut archetype = Archetype {
+            entities: vec![],
+            entity_indices: HashMap::new(),
+            column_indices,
+        };
+        archetype.set_entities(entities);
+        archetype
+    }
+
+    pub(crate) fn push_entity(&mut self, entity: Entity) {
+        self.entity_indices.insert(entity, self.entities.len());
+        self.entities.push(entity);
+    }
+
+    /// Removes the entity at `idx`, moving the last entity into its place
+    pub(crate) fn swap_remove_entity(&mut self, idx: usize) -> Entity {
+        let removed = self.entities.swap_remove(idx);
+        self.entity_indices.remove(&removed);
+        if let Some(&moved) = self.entities.get(idx) {
+            self.entity_indices.insert(moved, idx);
+        }
+        removed
+    }
+
+    pub(crate) fn set_entities(&mut self, entities: Vec<Entity>) {
+        self.entity_indices = entities
+            .iter()
+            .enumerate()
+            .map(|(idx, &entity)| (entity, idx))
+            .collect();
+        self.entities = entities;
     }
 }
 
+/// Source of `World::id`, never reused so that a world created where another was dropped
+/// still gets a new id
+static NEXT_WORLD_ID: AtomicU64 = AtomicU64::new(0);
+
 pub struct World {
+    /// Unique among all worlds created by this process
+    pub(crate) id: u64,
     pub(crate) entities: Entities,
     pub(crate) archetypes: Vec<Archetype>,
     pub(crate) columns: HashMap<EcsTypeId, RefCell<Vec<Box<dyn Storage>>>>,
+    /// Storage for components registered as `StorageKind::Sparse`
+    pub(crate) sparse_sets: HashMap<EcsTypeId, RefCell<Box<dyn ErasedSparseSet>>>,
     next_ecs_type_id: EcsTypeId,
     pub(crate) ecs_type_ids: HashMap<TypeId, EcsTypeId>,
+    on_remove_hooks: HashMap<EcsTypeId, Vec<Box<dyn FnMut(Entity)>>>,
+    on_insert_hooks: HashMap<EcsTypeId, Vec<Box<dyn FnMut(Entity, bool)>>>,
+    interned_dynamic_ids: HashMap<String, EcsTypeId>,
+    component_layouts: HashMap<EcsTypeId, std::alloc::Layout>,
+    archetype_creation_count: usize,
+    /// Incremented whenever archetypes are removed, as that changes archetype indices
+    pub(crate) archetype_removal_count: usize,
+    peak_archetype_count: usize,
 }
 
 impl World {
     pub fn new() -> World {
+        Self::with_capacity(0)
+    }
+
+    /// Preallocates space for `entities` entities with no components
+    pub fn with_capacity(entities: usize) -> World {
         World {
-            entities: Entities::new(),
-            archetypes: vec![Archetype {
-                entities: vec![],
-                column_indices: HashMap::new(),
-            }],
+            id: NEXT_WORLD_ID.fetch_add(1, Ordering::Relaxed),
+            entities: Entities::with_capacity(entities),
+            archetypes: vec![Archetype::new(Vec::with_capacity(entities), HashMap::new())],
             columns: HashMap::new(),
+            sparse_sets: HashMap::new(),
             next_ecs_type_id: EcsTypeId(0),
             ecs_type_ids: HashMap::new(),
+            on_remove_hooks: HashMap::new(),
+            on_insert_hooks: HashMap::new(),
+            interned_dynamic_ids: HashMap::new(),
+            component_layouts: HashMap::new(),
+            archetype_creation_count: 0,
+            archetype_removal_count: 0,
+            peak_archetype_count: 1,
         }
     }
 
@@ -200,9 +354,58 @@ impl World {
             .expect("girl why u making usize::MAX ecs_type_ids");
         self.columns
             .insert(ecs_type_id, RefCell::new(vec![Box::new(Vec::<T>::new())]));
+        self.component_layouts
+            .insert(ecs_type_id, std::alloc::Layout::new::<T>());
         Some(ecs_type_id)
     }
 
+    /// Chooses how `T` is stored. Once `T` is registered as `StorageKind::Sparse` the typed
+    /// component methods such as `insert_component` and `get_component` use a `SparseSet`
+    /// instead of archetype columns. Queries read it with `Sparse<&T>`, querying `&T` or
+    /// `&mut T` errors.
+    ///
+    /// Panics if `T` was already registered as sparse and `kind` is `StorageKind::Table`, or
+    /// when registering `T` as sparse if any archetype already stores `T`.
+    pub fn register_component<T: Component>(&mut self, kind: StorageKind) -> EcsTypeId {
+        let id = self.type_to_ecs_type_id_or_create::<T>();
+        if self.storage_kind(id) == kind {
+            return id;
+        }
+        assert!(
+            kind == StorageKind::Sparse,
+            "`{}` was already registered as sparse",
+            std::any::type_name::<T>()
+        );
+        assert!(
+            self.archetypes
+                .iter()
+                .all(|archetype| archetype.column_indices.contains_key(&id) == false),
+            "`{}` is already stored in archetypes",
+            std::any::type_name::<T>()
+        );
+        self.sparse_sets
+            .insert(id, RefCell::new(Box::new(SparseSet::<T>::new())));
+        id
+    }
+
+    pub fn storage_kind(&self, id: EcsTypeId) -> StorageKind {
+        match self.sparse_sets.contains_key(&id) {
+            true => StorageKind::Sparse,
+            false => StorageKind::Table,
+        }
+    }
+
+    fn sparse_set_cell<T: Component>(&self) -> Option<&RefCell<Box<dyn ErasedSparseSet>>> {
+        self.sparse_sets.get(&self.type_to_ecs_type_id::<T>()?)
+    }
+
+    /// Every `T` if `T` is registered as sparse. Returns `None` if it isn't or if the sparse
+    /// set is borrowed mutably.
+    pub fn sparse_set<T: Component>(&self) -> Option<cell::Ref<'_, SparseSet<T>>> {
+        let set = self.sparse_set_cell::<T>()?.try_borrow().ok()?;
+        Some(cell::Ref::map(set, |set| set.downcast_ref::<T>()))
+    }
+
     pub fn new_dynamic_ecs_type_id(&mut self, layout: std::alloc::Layout) -> EcsTypeId {
         let ecs_type_id = self.next_ecs_type_id;
         self.next_ecs_type_id.0 = ecs_type_id
@@ -215,23 +418,149 @@ impl World {
                 layout,
             ))]),
         );
+        self.component_layouts.insert(ecs_type_id, layout);
         ecs_type_id
     }
 
+    /// Like `new_dynamic_ecs_type_id` except that calling this again with the same `tag`
+    /// returns the `EcsTypeId` created by the first call instead of making a new column.
+    ///
+    /// Panics if `tag` was previously interned with a different layout.
+    pub fn interned_dynamic_ecs_type_id(
+        &mut self,
+        layout: std::alloc::Layout,
+        tag: &str,
+    ) -> EcsTypeId {
+        if let Some(&id) = self.interned_dynamic_ids.get(tag) {
+            let interned_layout = self.component_layouts[&id];
+            assert!(
+                interned_layout == layout,
+                "tag `{}` was interned with {:?} but requested with {:?}",
+                tag,
+                interned_layout,
+                layout
+            );
+            return id;
+        }
+        let id = self.new_dynamic_ecs_type_id(layout);
+        self.interned_dynamic_ids.insert(tag.to_owned(), id);
+        id
+    }
+
+    pub fn component_layout(&self, id: EcsTypeId) -> Option<std::alloc::Layout> {
+        self.component_layouts.get(&id).copied()
+    }
+
+    pub fn iter_component_ids(&self) -> impl Iterator<Item = EcsTypeId> + '_ {
+        self.columns.keys().copied()
+    }
+
     pub fn is_alive(&self, entity: Entity) -> bool {
         self.entities.is_alive(entity)
     }
 
+    /// Every live entity in ascending id order, unlike iterating archetypes which yields
+    /// entities grouped by archetype. Reserved entities are not included.
+    pub fn iter_entities_sorted(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.iter_alive()
+    }
+
+    /// Whether `entity` has been reserved but not yet made alive by `flush_reserved`
+    pub fn is_reserved(&self, entity: Entity) -> bool {
+        self.entities.is_reserved(entity)
+    }
+
     pub fn spawn(&mut self) -> EntityBuilder<'_> {
-        let entity = self.entities.spawn(|entity| {
-            self.archetypes[0].entities.push(entity);
-        });
+        let entity = self.spawn_empty();
         EntityBuilder {
             entity,
             world: self,
         }
     }
 
+    pub fn spawn_empty(&mut self) -> Entity {
+        self.entities.spawn(|entity| {
+            self.archetypes[0].push_entity(entity);
+        })
+    }
+
+    /// Spawns `count` entities with `f(i)` inserted into the `i`th one. The entities are
+    /// placed straight into the archetype with only a `T` column rather than being moved
+    /// there from the empty archetype one at a time. Sparse `T`s stay in the empty archetype.
+    pub fn spawn_batch_with<T: Component>(
+        &mut self,
+        count: usize,
+        f: impl FnMut(usize) -> T,
+    ) -> Vec<Entity> {
+        // `f` is run before any entity is spawned so that if it panics the archetype's
+        // entities and its column are not left with different lengths
+        let values = (0..count).map(f).collect::<Vec<_>>();
+        self.flush_reserved();
+        let ecs_type_id = self.type_to_ecs_type_id_or_create::<T>();
+        if self.storage_kind(ecs_type_id) == StorageKind::Sparse {
+            return values
+                .into_iter()
+                .map(|value| {
+                    let entity = self.spawn_empty();
+                    self.insert_component(entity, value);
+                    entity
+                })
+                .collect();
+        }
+        let archetype_id = self.get_or_insert_archetype_from_insert(0, ecs_type_id);
+
+        let entities = (0..count)
+            .map(|_| self.entities.reserve_entity())
+            .collect::<Vec<_>>();
+        let archetype = &mut self.archetypes[archetype_id];
+        self.entities
+            .fix_reserved_entities(|entity| archetype.push_entity(entity));
+
+        let column_idx = archetype.column_indices[&ecs_type_id];
+        let column = &mut self.columns.get_mut(&ecs_type_id).unwrap().get_mut()[column_idx];
+        let column = column.as_typed_storage_mut().unwrap();
+        for (&entity, value) in entities.iter().zip(values) {
+            self.entities.meta_mut(entity).unwrap().archetype = archetype_id;
+            column.push(value);
+        }
+        for &entity in &entities {
+            self.run_on_insert_hooks(ecs_type_id, entity, false);
+        }
+        entities
+    }
+
+    /// Spawns one entity per value with that value inserted, see `spawn_batch_with`.
+    pub fn spawn_each<T: Component>(&mut self, values: impl IntoIterator<Item = T>) -> Vec<Entity> {
+        let mut values = values.into_iter().collect::<Vec<_>>().into_iter();
+        self.spawn_batch_with(values.len(), |_| values.next().unwrap())
+    }
+
+    /// Reserves an entity id without spawning it. The entity is not alive, and so can't
+    /// be used, until `flush_reserved` is called. Inserting components and applying
+    /// commands both flush reserved entities.
+    pub fn reserve_entity(&self) -> Entity {
+        self.entities.reserve_entity()
+    }
+
+    /// Number of entities reserved but not yet made alive by `flush_reserved`
+    pub fn reserved_entity_count(&self) -> usize {
+        self.entities.num_reserved()
+    }
+
+    /// Number of entity slots that have been spawned or flushed into, including slots
+    /// whose entity has since been despawned. Does not include reserved entities.
+    pub fn materialized_entity_count(&self) -> usize {
+        self.entities.num_materialized()
+    }
+
+    /// Makes entities reserved through `Commands::spawn` or `reserve_entity` alive, placing them in the
+    /// empty archetype. Commands for them that have not been applied yet still apply
+    /// afterwards.
+    pub fn flush_reserved(&mut self) {
+        self.entities
+            .fix_reserved_entities(|reserved| self.archetypes[0].push_entity(reserved));
+    }
+
     pub fn entity_builder(&mut self, entity: Entity) -> EntityBuilder<'_> {
         EntityBuilder {
             entity,
@@ -239,26 +568,140 @@ impl World {
         }
     }
 
+    /// Removes the entity and all of its components. The last entity in its archetype is
+    /// swap-removed into its slot, in `Archetype::entities` and in every column, so the
+    /// two always stay the same length. Queries borrow the world so they can never observe
+    /// the archetype mid-despawn, a query made afterwards sees the new order.
     pub fn despawn(&mut self, entity: Entity) {
         self.entities
-            .fix_reserved_entities(|reserved| self.archetypes[0].entities.push(reserved))
+            .fix_reserved_entities(|reserved| self.archetypes[0].push_entity(reserved))
             .despawn(entity, |meta| {
                 let archetype = &mut self.archetypes[meta.archetype];
                 let entity_idx = archetype.get_entity_idx(entity).unwrap();
-                archetype.entities.swap_remove(entity_idx);
+                archetype.swap_remove_entity(entity_idx);
 
                 for (ty_id, column_idx) in archetype.column_indices.iter() {
+                    for hook in self.on_remove_hooks.get_mut(ty_id).into_iter().flatten() {
+                        hook(entity);
+                    }
                     RefCell::get_mut(&mut self.columns.get_mut(ty_id).unwrap())[*column_idx]
                         .swap_remove_and_drop(entity_idx);
                 }
+                for (ty_id, sparse_set) in self.sparse_sets.iter_mut() {
+                    let sparse_set = sparse_set.get_mut();
+                    if sparse_set.contains_entity(entity) {
+                        for hook in self.on_remove_hooks.get_mut(ty_id).into_iter().flatten() {
+                            hook(entity);
+                        }
+                        sparse_set.remove_entity(entity);
+                    }
+                }
             });
     }
 
+    /// Despawns every entity and removes every archetype other than the empty archetype.
+    /// Component ids stay registered, and the generations of despawned entities are kept
+    /// so handles to them are still known to be dead once their slots are reused.
+    pub fn clear(&mut self) {
+        self.flush_reserved();
+        for archetype in &self.archetypes {
+            for ty_id in archetype.column_indices.keys() {
+                for hook in self.on_remove_hooks.get_mut(ty_id).into_iter().flatten() {
+                    for &entity in &archetype.entities {
+                        hook(entity);
+                    }
+                }
+            }
+        }
+        for (ty_id, sparse_set) in self.sparse_sets.iter_mut() {
+            let sparse_set = sparse_set.get_mut();
+            for hook in self.on_remove_hooks.get_mut(ty_id).into_iter().flatten() {
+                sparse_set.for_each_entity(&mut |entity| hook(entity));
+            }
+            sparse_set.clear();
+        }
+
+        for storages in self.columns.values_mut() {
+            let storages = storages.get_mut();
+            for storage in storages.iter_mut() {
+                storage.truncate(0);
+            }
+            // only keep the template storage
+            storages.truncate(1);
+        }
+        if self.archetypes.len() > 1 {
+            self.archetypes.truncate(1);
+            self.archetype_removal_count += 1;
+        }
+        self.archetypes[0].set_entities(vec![]);
+        self.entities
+            .fix_reserved_entities(|_| unreachable!())
+            .despawn_all();
+    }
+
+    /// Despawns every entity for which `pred` returns true, returns how many were despawned
+    pub fn despawn_if(&mut self, pred: impl Fn(Entity, &Archetype) -> bool) -> usize {
+        let to_despawn = self
+            .archetypes
+            .iter()
+            .flat_map(|archetype| {
+                archetype
+                    .entities
+                    .iter()
+                    .copied()
+                    .filter(|&entity| pred(entity, archetype))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        for &entity in to_despawn.iter() {
+            self.despawn(entity);
+        }
+        to_despawn.len()
+    }
+
+    /// Registers a hook that is called with the entity whenever a component of
+    /// type `id` is removed from it, either by `remove_component` or `despawn`.
+    /// Hooks run before the component's data is dropped.
+    pub fn on_remove(&mut self, id: EcsTypeId, hook: impl FnMut(Entity) + 'static) {
+        self.on_remove_hooks
+            .entry(id)
+            .or_default()
+            .push(Box::new(hook));
+    }
+
+    fn run_on_remove_hooks(&mut self, id: EcsTypeId, entity: Entity) {
+        for hook in self.on_remove_hooks.get_mut(&id).into_iter().flatten() {
+            hook(entity);
+        }
+    }
+
+    /// Registers a hook that is called with the entity after a component of type
+    /// `id` has been written to it by `insert_component`. The `bool` is `true` if
+    /// the insert overwrote an existing component.
+    pub fn on_insert(&mut self, id: EcsTypeId, hook: impl FnMut(Entity, bool) + 'static) {
+        self.on_insert_hooks
+            .entry(id)
+            .or_default()
+            .push(Box::new(hook));
+    }
+
+    fn run_on_insert_hooks(&mut self, id: EcsTypeId, entity: Entity, overwrite: bool) {
+        for hook in self.on_insert_hooks.get_mut(&id).into_iter().flatten() {
+            hook(entity, overwrite);
+        }
+    }
+
     pub fn has_component<T: Component>(&self, entity: Entity) -> Option<bool> {
         let ecs_type_id = match self.type_to_ecs_type_id::<T>() {
             Some(id) => id,
             None => return Some(false),
         };
+        if let Some(set) = self.sparse_sets.get(&ecs_type_id) {
+            if self.is_alive(entity) == false {
+                return None;
+            }
+            return Some(set.borrow().downcast_ref::<T>().contains(entity));
+        }
         self.has_component_dynamic(entity, ecs_type_id)
     }
 
@@ -267,7 +710,13 @@ impl World {
         Some(self.archetypes[archetype].column_indices.get(&id).is_some())
     }
 
-    pub fn get_component<T: Component>(&self, entity: Entity) -> Option<cell::Ref<T>> {
+    /// Returns `None` if the entity does not have a `T` component or if the `T` column
+    /// is currently locked by a mutable query.
+    pub fn get_component<T: Component>(&self, entity: Entity) -> Option<cell::Ref<'_, T>> {
+        if let Some(set) = self.sparse_set_cell::<T>() {
+            let set = set.try_borrow().ok()?;
+            return cell::Ref::filter_map(set, |set| set.downcast_ref::<T>().get(entity)).ok();
+        }
         if self.has_component::<T>(entity)? == false {
             return None;
         }
@@ -278,16 +727,27 @@ impl World {
         let entity_idx = archetype.get_entity_idx(entity).unwrap();
         let column_idx = archetype.column_indices[&ecs_type_id];
         Some(cell::Ref::map(
-            self.get_column(column_idx, ecs_type_id),
+            self.get_column(column_idx, ecs_type_id)?,
             |col| &col.as_typed_storage().unwrap().as_vec::<T>().unwrap()[entity_idx],
         ))
     }
 
+    /// Lazily calls `get_component` for each of `entities`, yielding `None` for entities
+    /// that are dead or do not have a `T` component.
+    pub fn get_components<'a, T: Component>(
+        &'a self,
+        entities: &'a [Entity],
+    ) -> impl Iterator<Item = Option<cell::Ref<'a, T>>> + 'a {
+        entities
+            .iter()
+            .map(move |&entity| self.get_component::<T>(entity))
+    }
+
     pub fn get_component_dynamic(
         &self,
         entity: Entity,
         id: EcsTypeId,
-    ) -> Option<(usize, cell::Ref<dyn Storage>)> {
+    ) -> Option<(usize, cell::Ref<'_, dyn Storage>)> {
         if self.has_component_dynamic(entity, id)? == false {
             return None;
         }
@@ -296,10 +756,17 @@ impl World {
         let archetype = &self.archetypes[archetype_id];
         let entity_idx = archetype.get_entity_idx(entity).unwrap();
         let column_idx = archetype.column_indices[&id];
-        Some((entity_idx, self.get_column(column_idx, id)))
+        Some((entity_idx, self.get_column(column_idx, id)?))
     }
 
-    pub fn get_component_mut<T: Component>(&self, entity: Entity) -> Option<cell::RefMut<T>> {
+    /// Returns `None` if the entity does not have a `T` component or if the `T` column
+    /// is currently locked by a query.
+    pub fn get_component_mut<T: Component>(&self, entity: Entity) -> Option<cell::RefMut<'_, T>> {
+        if let Some(set) = self.sparse_set_cell::<T>() {
+            let set = set.try_borrow_mut().ok()?;
+            return cell::RefMut::filter_map(set, |set| set.downcast_mut::<T>().get_mut(entity))
+                .ok();
+        }
         if self.has_component::<T>(entity)? == false {
             return None;
         }
@@ -310,7 +777,7 @@ impl World {
         let entity_idx = archetype.get_entity_idx(entity).unwrap();
         let column_idx = archetype.column_indices[&ecs_type_id];
         Some(cell::RefMut::map(
-            self.get_column_mut(column_idx, ecs_type_id),
+            self.get_column_mut(column_idx, ecs_type_id)?,
             |vec| {
                 &mut vec
                     .as_typed_storage_mut()
@@ -325,7 +792,7 @@ impl World {
         &self,
         entity: Entity,
         id: EcsTypeId,
-    ) -> Option<(usize, cell::RefMut<dyn Storage>)> {
+    ) -> Option<(usize, cell::RefMut<'_, dyn Storage>)> {
         if self.has_component_dynamic(entity, id)? == false {
             return None;
         }
@@ -334,7 +801,7 @@ impl World {
         let archetype = &self.archetypes[archetype_id];
         let entity_idx = archetype.get_entity_idx(entity).unwrap();
         let column_idx = archetype.column_indices[&id];
-        Some((entity_idx, self.get_column_mut(column_idx, id)))
+        Some((entity_idx, self.get_column_mut(column_idx, id)?))
     }
 
     pub fn get_component_mut_dynamic_ct(
@@ -357,10 +824,20 @@ impl World {
     }
 
     pub fn remove_component<T: Component>(&mut self, entity: Entity) -> Option<T> {
+        if self.sparse_set_cell::<T>().is_some() {
+            if self.has_component::<T>(entity)? == false {
+                return None;
+            }
+            let ecs_type_id = self.type_to_ecs_type_id::<T>()?;
+            self.run_on_remove_hooks(ecs_type_id, entity);
+            let set = self.sparse_sets.get_mut(&ecs_type_id).unwrap().get_mut();
+            return set.downcast_mut::<T>().remove(entity);
+        }
         if self.has_component::<T>(entity)? == false {
             return None;
         }
         let ecs_type_id = self.type_to_ecs_type_id::<T>()?;
+        self.run_on_remove_hooks(ecs_type_id, entity);
 
         let (entity_idx, old_archetype) = self.move_entity_from_remove(entity, ecs_type_id)?;
         let column_idx = *old_archetype.column_indices.get(&ecs_type_id).unwrap();
@@ -374,6 +851,38 @@ impl World {
         )
     }
 
+    /// Removes every `T` component from the world, returning them along with the
+    /// entity they were removed from.
+    pub fn drain_components<T: Component>(&mut self) -> Vec<(Entity, T)> {
+        let entities = match self.type_to_ecs_type_id::<T>() {
+            Some(id) if self.storage_kind(id) == StorageKind::Sparse => self.sparse_sets[&id]
+                .borrow()
+                .downcast_ref::<T>()
+                .iter()
+                .map(|(entity, _)| entity)
+                .collect::<Vec<_>>(),
+            Some(id) => self
+                .archetypes
+                .iter()
+                .filter(|archetype| archetype.column_indices.contains_key(&id))
+                .flat_map(|archetype| archetype.entities.iter().copied())
+                .collect::<Vec<_>>(),
+            None => return Vec::new(),
+        };
+        entities
+            .into_iter()
+            .map(|entity| (entity, self.remove_component::<T>(entity).unwrap()))
+            .collect()
+    }
+
+    /// Replaces every `T` component with the `U` returned by `f`, overwriting any `U`
+    /// the entity already had.
+    pub fn map_component<T: Component, U: Component>(&mut self, mut f: impl FnMut(T) -> U) {
+        for (entity, component) in self.drain_components::<T>() {
+            self.insert_component(entity, f(component));
+        }
+    }
+
     pub fn remove_component_dynamic(
         &mut self,
         entity: Entity,
@@ -382,6 +891,7 @@ impl World {
         if self.has_component_dynamic(entity, id)? == false {
             return None;
         }
+        self.run_on_remove_hooks(id, entity);
 
         let (entity_idx, old_archetype) = self.move_entity_from_remove(entity, id)?;
 
@@ -395,6 +905,24 @@ impl World {
         )
     }
 
+    /// Removes the component and returns a copy of the bytes it held
+    pub fn take_component_dynamic(&mut self, entity: Entity, id: EcsTypeId) -> Option<Vec<u8>> {
+        if self.has_component_dynamic(entity, id)? == false {
+            return None;
+        }
+        self.run_on_remove_hooks(id, entity);
+
+        let (entity_idx, old_archetype) = self.move_entity_from_remove(entity, id)?;
+
+        let column_idx = *old_archetype.column_indices.get(&id).unwrap();
+        Some(
+            self.columns.get_mut(&id).unwrap().get_mut()[column_idx]
+                .as_erased_storage_mut()
+                .unwrap()
+                .swap_remove_to_bytes(entity_idx),
+        )
+    }
+
     /// Moves an entity between archetypes and all its components to new columns
     /// from a `remove` operation. Caller should handle actually removing data
     /// of `removed_id` from the column of the old archetype
@@ -413,25 +941,41 @@ impl World {
             archetype: new_archetype_id,
         };
         let (old_archetype, new_archetype) =
-            get_two(&mut self.archetypes, archetype_id, new_archetype_id);
+            get_two_archetypes_mut(&mut self.archetypes, archetype_id, new_archetype_id);
 
         let entity_idx = old_archetype.get_entity_idx(entity).unwrap();
-        old_archetype.entities.swap_remove(entity_idx);
+        old_archetype.swap_remove_entity(entity_idx);
 
         for (column_type_id, &new_column) in new_archetype.column_indices.iter() {
             let old_column = *old_archetype.column_indices.get(column_type_id).unwrap();
-            let mut storages = RefCell::borrow_mut(self.columns.get(column_type_id).unwrap());
-            let (old_column, new_column) = get_two(&mut *storages, old_column, new_column);
+            // `&mut self` so no need to go through the `RefCell`'s borrow tracking
+            let storages = self.columns.get_mut(column_type_id).unwrap().get_mut();
+            let (old_column, new_column) = get_two(storages, old_column, new_column);
             old_column.swap_remove_move_to(new_column, entity_idx)
         }
-        new_archetype.entities.push(entity);
+        new_archetype.push_entity(entity);
         Some((entity_idx, old_archetype))
     }
 
     pub fn insert_component<T: Component>(&mut self, entity: Entity, component: T) -> Option<T> {
+        self.flush_reserved();
         let ecs_type_id = self.type_to_ecs_type_id_or_create::<T>();
-        if let Some(mut old_component) = self.get_component_mut::<T>(entity) {
-            return Some(std::mem::replace(&mut *old_component, component));
+        if self.storage_kind(ecs_type_id) == StorageKind::Sparse {
+            if self.is_alive(entity) == false {
+                return None;
+            }
+            let set = self.sparse_sets.get_mut(&ecs_type_id).unwrap();
+            let old_component = set.get_mut().downcast_mut::<T>().insert(entity, component);
+            self.run_on_insert_hooks(ecs_type_id, entity, old_component.is_some());
+            return old_component;
+        }
+        if self.has_component::<T>(entity) == Some(true) {
+            let old_component = std::mem::replace(
+                &mut *self.get_component_mut::<T>(entity).unwrap(),
+                component,
+            );
+            self.run_on_insert_hooks(ecs_type_id, entity, true);
+            return Some(old_component);
         }
 
         let new_archetype = self.move_entity_from_insert(entity, ecs_type_id)?;
@@ -441,81 +985,630 @@ impl World {
             .as_typed_storage_mut()
             .unwrap()
             .push(component);
+        self.run_on_insert_hooks(ecs_type_id, entity, false);
         None
     }
 
+    /// Calls `on_update` on the entity's `T` if it has one, otherwise inserts `on_insert()`.
+    /// Does nothing if the entity is dead.
+    pub fn upsert_component<T: Component>(
+        &mut self,
+        entity: Entity,
+        on_insert: impl FnOnce() -> T,
+        on_update: impl FnOnce(&mut T),
+    ) {
+        match self.has_component::<T>(entity) {
+            Some(true) => on_update(&mut *self.get_component_mut::<T>(entity).unwrap()),
+            Some(false) if self.is_alive(entity) => {
+                self.insert_component(entity, on_insert());
+            }
+            _ => (),
+        }
+    }
+
+    /// Inserts every `(entity, component)` pair, overwriting components that are
+    /// already present. Pairs for dead entities are skipped.
+    pub fn extend_components<T: Component>(&mut self, iter: impl IntoIterator<Item = (Entity, T)>) {
+        for (entity, component) in iter {
+            self.insert_component(entity, component);
+        }
+    }
+
+    /// Inserts a clone of `value` into every entity in `entities`, overwriting components
+    /// that are already present. Entities are moved one archetype at a time so the new
+    /// archetype is only looked up once per archetype rather than once per entity.
+    pub fn insert_shared_component<T: Component + Clone>(&mut self, entities: &[Entity], value: T) {
+        self.flush_reserved();
+        let ecs_type_id = self.type_to_ecs_type_id_or_create::<T>();
+        if self.storage_kind(ecs_type_id) == StorageKind::Sparse {
+            for &entity in entities {
+                self.insert_component(entity, value.clone());
+            }
+            return;
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        let mut to_move = vec![];
+        for &entity in entities {
+            if seen.insert(entity) == false {
+                continue;
+            }
+            match self.has_component::<T>(entity) {
+                None => (),
+                Some(true) => {
+                    self.insert_component(entity, value.clone());
+                }
+                Some(false) => {
+                    to_move.push((self.entities.meta(entity).unwrap().archetype, entity));
+                }
+            }
+        }
+        to_move.sort_by_key(|&(archetype, _)| archetype);
+
+        let mut new_archetype_for = None;
+        for (archetype, entity) in to_move {
+            let new_archetype_id = match new_archetype_for {
+                Some((old, new)) if old == archetype => new,
+                _ => {
+                    let new = self.get_or_insert_archetype_from_insert(archetype, ecs_type_id);
+                    new_archetype_for = Some((archetype, new));
+                    new
+                }
+            };
+            let new_archetype = self.move_entity_from_insert_to(entity, new_archetype_id);
+            let column_idx = new_archetype.column_indices[&ecs_type_id];
+            self.columns.get_mut(&ecs_type_id).unwrap().get_mut()[column_idx]
+                .as_typed_storage_mut()
+                .unwrap()
+                .push(value.clone());
+            self.run_on_insert_hooks(ecs_type_id, entity, false);
+        }
+    }
+
+    /// Calls `f` on every `T` component in the world. Iterates each archetype's column
+    /// as a slice, so this is faster than the equivalent `Query<(Entity, &mut T)>`.
+    pub fn for_each_mut<T: Component>(&mut self, mut f: impl FnMut(Entity, &mut T)) {
+        let ecs_type_id = match self.type_to_ecs_type_id::<T>() {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(set) = self.sparse_sets.get_mut(&ecs_type_id) {
+            for (entity, component) in set.get_mut().downcast_mut::<T>().iter_mut() {
+                f(entity, component);
+            }
+            return;
+        }
+        let columns = self.columns.get_mut(&ecs_type_id).unwrap().get_mut();
+        for archetype in self.archetypes.iter() {
+            let column_idx = match archetype.column_indices.get(&ecs_type_id) {
+                Some(&idx) => idx,
+                None => continue,
+            };
+            let components = columns[column_idx]
+                .as_typed_storage_mut()
+                .unwrap()
+                .as_vec_mut::<T>()
+                .unwrap();
+            for (&entity, component) in archetype.entities.iter().zip(components.iter_mut()) {
+                f(entity, component);
+            }
+        }
+    }
+
+    /// Iterates every component of the dynamic component `id` along with the entity
+    /// it belongs to. Panics if `id` is a static component.
+    pub fn iter_dynamic_mut(
+        &mut self,
+        id: EcsTypeId,
+    ) -> impl Iterator<Item = (Entity, LtPtrMut<'_>)> + '_ {
+        let size = self
+            .component_layouts
+            .get(&id)
+            .map_or(0, |layout| layout.size());
+        let columns = match self.columns.get_mut(&id) {
+            Some(columns) => columns.get_mut().as_mut_slice(),
+            None => &mut [],
+        };
+        let mut column_entities = vec![None; columns.len()];
+        for archetype in self.archetypes.iter() {
+            if let Some(&column_idx) = archetype.column_indices.get(&id) {
+                column_entities[column_idx] = Some(archetype.entities.as_slice());
+            }
+        }
+        columns
+            .iter_mut()
+            .zip(column_entities)
+            .filter_map(|(storage, entities)| Some((storage, entities?)))
+            .flat_map(move |(storage, entities)| {
+                let storage = storage
+                    .as_erased_storage_mut()
+                    .expect("`iter_dynamic_mut` called with a static component's `EcsTypeId`");
+                entities
+                    .iter()
+                    .copied()
+                    .zip(storage.iter_mut().map(move |ptr| {
+                        let ptr =
+                            std::ptr::slice_from_raw_parts_mut(ptr as *mut MaybeUninit<u8>, size);
+                        LtPtrMut(Default::default(), ptr)
+                    }))
+            })
+    }
+
+    /// Flushes reserved entities before inserting, so inserting into an entity from
+    /// `reserve_entity` makes it alive first rather than writing to a missing row.
     pub fn insert_component_dynamic(
         &mut self,
         entity: Entity,
         id: EcsTypeId,
         write_fn: impl for<'a> FnOnce(LtPtrWriteOnly<'a>),
     ) -> Option<LtPtrOwn<'_>> {
+        self.flush_reserved();
         // no `if let` bcos borrowck is bad, gimme polonius >:(
-        if self.get_component_mut_dynamic_ct(entity, id).is_some() {
-            let (entity_idx, storage) = self.get_component_mut_dynamic_ct(entity, id).unwrap();
-            let storage = storage.as_erased_storage_mut().unwrap();
+        if self.has_component_dynamic(entity, id) == Some(true) {
+            let archetype = &self.archetypes[self.entities.meta(entity).unwrap().archetype];
+            let entity_idx = archetype.get_entity_idx(entity).unwrap();
+            let column_idx = archetype.column_indices[&id];
+            // borrow `columns` directly instead of going through `get_component_mut_dynamic_ct`
+            // so that the insert hooks can still be run while `inserted_over` is alive
+            let storage = self.columns.get_mut(&id).unwrap().get_mut()[column_idx]
+                .as_erased_storage_mut()
+                .unwrap();
             let (inserted_over, uninit_idx) = storage.copy_to_insert_over_space(entity_idx);
             write_fn(uninit_idx);
+            for hook in self.on_insert_hooks.get_mut(&id).into_iter().flatten() {
+                hook(entity, true);
+            }
             return Some(inserted_over);
         }
 
-        let new_archetype = self.move_entity_from_insert(entity, id)?;
-
-        let column_idx = *new_archetype.column_indices.get(&id).unwrap();
+        if self.is_alive(entity) == false {
+            return None;
+        }
 
-        let mut column = self.get_column_mut(column_idx, id);
-        let erased_storage = column.as_erased_storage_mut().unwrap();
+        // Write the component before moving the entity so that if `write_fn` panics
+        // the entity is left in its old archetype with all columns the right length.
+        let archetype_id = self.entities.meta(entity).unwrap().archetype;
+        let new_archetype_id = self.get_or_insert_archetype_from_insert(archetype_id, id);
+        let column_idx = self.archetypes[new_archetype_id].column_indices[&id];
+        let erased_storage = self.columns.get_mut(&id).unwrap().get_mut()[column_idx]
+            .as_erased_storage_mut()
+            .unwrap();
         let num_elements = erased_storage.num_elements();
         erased_storage.realloc_if_full();
         write_fn(LtPtrWriteOnly(
             Default::default(),
             erased_storage.get_element_ptr_mut(num_elements).1,
         ));
-        erased_storage.incr_len();
+
+        self.move_entity_from_insert(entity, id).unwrap();
+        self.columns.get_mut(&id).unwrap().get_mut()[column_idx]
+            .as_erased_storage_mut()
+            .unwrap()
+            .incr_len();
+        self.run_on_insert_hooks(id, entity, false);
         None
     }
 
-    /// Moves an entity between archetypes and all its components to new columns
-    /// from an `insert` operation. Caller should handle actually inserting data
-    /// of `insert_id` into the column of the new archetype
-    fn move_entity_from_insert(
-        &mut self,
-        entity: Entity,
-        inserted_id: EcsTypeId,
-    ) -> Option<&mut Archetype> {
-        if self.is_alive(entity) == false {
-            return None;
+    /// Inserts every `(id, bytes)` dynamic component into `entity`, overwriting components
+    /// that are already present. The entity is moved straight to the archetype with all of
+    /// the components instead of through one archetype per inserted component. Does
+    /// nothing if the entity is dead.
+    ///
+    /// # Panics
+    ///
+    /// If any id is out of range for this world, is for a static component, is in
+    /// `components` more than once, or if the length of its bytes is not the size of the
+    /// component.
+    pub fn insert_bundle_dynamic(&mut self, entity: Entity, components: &[(EcsTypeId, &[u8])]) {
+        for (idx, &(id, bytes)) in components.iter().enumerate() {
+            assert!(
+                self.columns.contains_key(&id),
+                "`insert_bundle_dynamic` called with unknown {:?}",
+                id
+            );
+            assert!(
+                self.columns[&id].borrow()[0].as_erased_storage().is_some(),
+                "`insert_bundle_dynamic` called with static component {:?}",
+                id
+            );
+            assert!(
+                components[..idx].iter().all(|&(other, _)| other != id),
+                "{:?} passed to `insert_bundle_dynamic` more than once",
+                id
+            );
+            let size = self.component_layouts[&id].size();
+            assert!(
+                bytes.len() == size,
+                "expected {} bytes for {:?} but got {}",
+                size,
+                id,
+                bytes.len()
+            );
         }
 
-        let archetype_id = self.entities.meta(entity).unwrap().archetype;
-        let new_archetype_id = self.get_or_insert_archetype_from_insert(archetype_id, inserted_id);
-        *self.entities.meta_mut(entity).unwrap() = EntityMeta {
-            archetype: new_archetype_id,
-        };
-        let (old_archetype, new_archetype) =
-            get_two(&mut self.archetypes, archetype_id, new_archetype_id);
+        self.flush_reserved();
+        if self.is_alive(entity) == false {
+            return;
+        }
 
-        let entity_idx = old_archetype.get_entity_idx(entity).unwrap();
-        old_archetype.entities.swap_remove(entity_idx);
+        let (existing, new): (Vec<_>, Vec<_>) = components
+            .iter()
+            .partition(|&&(id, _)| self.has_component_dynamic(entity, id) == Some(true));
+        for (id, bytes) in existing {
+            self.insert_component_dynamic_bytes(entity, id, bytes);
+        }
+        if new.is_empty() {
+            return;
+        }
 
-        for (column_type_id, &old_column) in old_archetype.column_indices.iter() {
-            let new_column = *new_archetype.column_indices.get(column_type_id).unwrap();
-            let mut storages = RefCell::borrow_mut(self.columns.get(column_type_id).unwrap());
-            let (old_column, new_column) = get_two(&mut *storages, old_column, new_column);
-            old_column.swap_remove_move_to(new_column, entity_idx);
+        let archetype_id = self.entities.meta(entity).unwrap().archetype;
+        let new_type_ids = self.archetypes[archetype_id]
+            .column_indices
+            .keys()
+            .copied()
+            .chain(new.iter().map(|&(id, _)| id))
+            .collect::<Vec<_>>();
+        let new_archetype_id = self.get_or_insert_archetype_from_ids(new_type_ids);
+        let new_archetype = self.move_entity_from_insert_to(entity, new_archetype_id);
+        let column_indices = new
+            .iter()
+            .map(|&(id, _)| new_archetype.column_indices[&id])
+            .collect::<Vec<_>>();
+        for (&(id, bytes), column_idx) in new.iter().zip(column_indices) {
+            let erased_storage = self.columns.get_mut(&id).unwrap().get_mut()[column_idx]
+                .as_erased_storage_mut()
+                .unwrap();
+            erased_storage.realloc_if_full();
+            erased_storage.copy_from_bytes(erased_storage.num_elements(), bytes);
+            erased_storage.incr_len();
+        }
+        for (id, _) in new {
+            self.run_on_insert_hooks(id, entity, false);
         }
-        new_archetype.entities.push(entity);
-        Some(new_archetype)
     }
 
-    pub fn query<Q: query::QueryParam>(
-        &self,
-    ) -> Result<query::Query<'_, Q>, errors::WorldBorrowError> {
-        Ok(query::Query {
-            w: self,
-            locks: Q::lock_from_world(self)?.map(|lock| (lock, Vec::new())),
-            dyn_params: Vec::new(),
-        })
+    /// Removes every dynamic component from `entity` returning their ids and bytes, sorted
+    /// by id. Static components are left on the entity.
+    pub fn take_all_dynamic(&mut self, entity: Entity) -> Vec<(EcsTypeId, Vec<u8>)> {
+        let archetype = match self.entities.meta(entity) {
+            Some(meta) => &self.archetypes[meta.archetype],
+            None => return Vec::new(),
+        };
+        let mut ids = archetype
+            .column_indices
+            .keys()
+            .copied()
+            .filter(|id| self.columns[id].borrow()[0].as_erased_storage().is_some())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.into_iter()
+            .map(|id| (id, self.take_component_dynamic(entity, id).unwrap()))
+            .collect()
+    }
+
+    /// Calls `visitor` with the id and bytes of each dynamic component on `entity` in
+    /// ascending id order. Static components are not visited, and neither are components
+    /// whose column is locked by a mutable query. Does nothing if the entity is dead.
+    pub fn visit_components(
+        &self,
+        entity: Entity,
+        visitor: &mut dyn FnMut(EcsTypeId, &[MaybeUninit<u8>]),
+    ) {
+        let (archetype, entity_idx) = match self.entities.meta(entity) {
+            Some(meta) => {
+                let archetype = &self.archetypes[meta.archetype];
+                (archetype, archetype.get_entity_idx(entity).unwrap())
+            }
+            None => return,
+        };
+        let mut columns = archetype
+            .column_indices
+            .iter()
+            .map(|(&id, &column_idx)| (id, column_idx))
+            .collect::<Vec<_>>();
+        columns.sort();
+        for (id, column_idx) in columns {
+            let storages = match self.columns[&id].try_borrow() {
+                Ok(storages) => storages,
+                Err(_) => continue,
+            };
+            let bytes = match storages[column_idx].as_erased_storage() {
+                Some(storage) => storage.element_to_bytes(entity_idx),
+                None => continue,
+            };
+            let bytes = bytes.into_iter().map(MaybeUninit::new).collect::<Vec<_>>();
+            visitor(id, &bytes);
+        }
+    }
+
+    /// Despawns `entity` returning the bytes of its dynamic components as returned by
+    /// `take_all_dynamic`. Static components are dropped as with `despawn`. Returns `None`
+    /// if the entity is dead.
+    pub fn despawn_returning(&mut self, entity: Entity) -> Option<Vec<(EcsTypeId, Vec<u8>)>> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+        let components = self.take_all_dynamic(entity);
+        self.despawn(entity);
+        Some(components)
+    }
+
+    /// Moves the dynamic component `id` from `from` to `to`, overwriting it if `to`
+    /// already has one. Returns false and does nothing if `from` does not have the
+    /// component or `to` is not alive.
+    pub fn move_component_dynamic(&mut self, id: EcsTypeId, from: Entity, to: Entity) -> bool {
+        if from == to
+            || self.has_component_dynamic(from, id) != Some(true)
+            || self.is_alive(to) == false
+        {
+            return false;
+        }
+        let bytes = self.take_component_dynamic(from, id).unwrap();
+        self.insert_component_dynamic_bytes(to, id, &bytes);
+        true
+    }
+
+    /// Copies the dynamic component `id` from `src` to `dst`, overwriting it if `dst`
+    /// already has one. Returns false and does nothing if `src` does not have the
+    /// component or `dst` is not alive.
+    pub fn copy_component_dynamic(&mut self, id: EcsTypeId, src: Entity, dst: Entity) -> bool {
+        if self.is_alive(dst) == false {
+            return false;
+        }
+        let bytes = match self.get_component_dynamic(src, id) {
+            Some((entity_idx, storage)) => storage
+                .as_erased_storage()
+                .unwrap()
+                .element_to_bytes(entity_idx),
+            None => return false,
+        };
+        if src != dst {
+            self.insert_component_dynamic_bytes(dst, id, &bytes);
+        }
+        true
+    }
+
+    /// Spawns a new entity with a copy of each of `src`'s dynamic components whose id is
+    /// in `keep`. Ids of static components, or of components `src` doesn't have, are
+    /// ignored. The new entity has no components if `src` is dead.
+    pub fn clone_entity_filtered(&mut self, src: Entity, keep: &[EcsTypeId]) -> Entity {
+        let components = keep
+            .iter()
+            .enumerate()
+            .filter(|&(idx, id)| keep[..idx].contains(id) == false)
+            .filter_map(|(_, &id)| {
+                let (entity_idx, storage) = self.get_component_dynamic(src, id)?;
+                let bytes = storage.as_erased_storage()?.element_to_bytes(entity_idx);
+                Some((id, bytes))
+            })
+            .collect::<Vec<_>>();
+        let dst = self.spawn_empty();
+        let components = components
+            .iter()
+            .map(|(id, bytes)| (*id, &bytes[..]))
+            .collect::<Vec<_>>();
+        self.insert_bundle_dynamic(dst, &components);
+        dst
+    }
+
+    /// `entity` must be alive
+    fn insert_component_dynamic_bytes(&mut self, entity: Entity, id: EcsTypeId, bytes: &[u8]) {
+        if self.has_component_dynamic(entity, id) == Some(true) {
+            let (entity_idx, storage) = self.get_component_mut_dynamic_ct(entity, id).unwrap();
+            storage
+                .as_erased_storage_mut()
+                .unwrap()
+                .copy_from_bytes(entity_idx, bytes);
+            self.run_on_insert_hooks(id, entity, true);
+            return;
+        }
+
+        let new_archetype = self.move_entity_from_insert(entity, id).unwrap();
+        let column_idx = *new_archetype.column_indices.get(&id).unwrap();
+        let erased_storage = self.columns.get_mut(&id).unwrap().get_mut()[column_idx]
+            .as_erased_storage_mut()
+            .unwrap();
+        erased_storage.realloc_if_full();
+        erased_storage.copy_from_bytes(erased_storage.num_elements(), bytes);
+        erased_storage.incr_len();
+        self.run_on_insert_hooks(id, entity, false);
+    }
+
+    /// Moves an entity between archetypes and all its components to new columns
+    /// from an `insert` operation. Caller should handle actually inserting data
+    /// of `insert_id` into the column of the new archetype
+    fn move_entity_from_insert(
+        &mut self,
+        entity: Entity,
+        inserted_id: EcsTypeId,
+    ) -> Option<&mut Archetype> {
+        if self.is_alive(entity) == false {
+            return None;
+        }
+
+        let archetype_id = self.entities.meta(entity).unwrap().archetype;
+        let new_archetype_id = self.get_or_insert_archetype_from_insert(archetype_id, inserted_id);
+        Some(self.move_entity_from_insert_to(entity, new_archetype_id))
+    }
+
+    /// Like `move_entity_from_insert` but with the new archetype already looked up,
+    /// it must have all the columns of the entity's current archetype.
+    fn move_entity_from_insert_to(
+        &mut self,
+        entity: Entity,
+        new_archetype_id: usize,
+    ) -> &mut Archetype {
+        let archetype_id = self.entities.meta(entity).unwrap().archetype;
+        *self.entities.meta_mut(entity).unwrap() = EntityMeta {
+            archetype: new_archetype_id,
+        };
+        let (old_archetype, new_archetype) =
+            get_two_archetypes_mut(&mut self.archetypes, archetype_id, new_archetype_id);
+
+        let entity_idx = old_archetype.get_entity_idx(entity).unwrap();
+        old_archetype.swap_remove_entity(entity_idx);
+
+        for (column_type_id, &old_column) in old_archetype.column_indices.iter() {
+            let new_column = *new_archetype.column_indices.get(column_type_id).unwrap();
+            // `&mut self` so no need to go through the `RefCell`'s borrow tracking
+            let storages = self.columns.get_mut(column_type_id).unwrap().get_mut();
+            let (old_column, new_column) = get_two(storages, old_column, new_column);
+            old_column.swap_remove_move_to(new_column, entity_idx);
+        }
+        new_archetype.push_entity(entity);
+        new_archetype
+    }
+
+    /// Number of elements in the column for `id` in `archetype`, `None` if the archetype
+    /// doesn't exist, has no column for `id`, or if the column is locked by a mutable query.
+    /// Always equal to the number of entities in the archetype.
+    pub fn len_in_archetype(&self, id: EcsTypeId, archetype: usize) -> Option<usize> {
+        let column_idx = *self.archetypes.get(archetype)?.column_indices.get(&id)?;
+        let columns = self.columns[&id].try_borrow().ok()?;
+        Some(columns[column_idx].num_elements())
+    }
+
+    /// The index of the archetype with exactly the components in `ids`, `None` if no
+    /// entity has had that set of components yet. Never creates an archetype.
+    pub fn archetype_for(&self, ids: &[EcsTypeId]) -> Option<usize> {
+        self.find_archetype_from_ids(ids)
+    }
+
+    /// Every archetype with a column for `id` along with its index
+    pub fn archetypes_containing(
+        &self,
+        id: EcsTypeId,
+    ) -> impl Iterator<Item = (usize, &Archetype)> + '_ {
+        self.archetypes
+            .iter()
+            .enumerate()
+            .filter(move |(_, archetype)| archetype.contains_component(id))
+    }
+
+    /// How many archetypes have been created over the lifetime of this world, not counting
+    /// the empty archetype every world starts with.
+    pub fn archetype_creation_count(&self) -> usize {
+        self.archetype_creation_count
+    }
+
+    /// The most entities that have been alive at once over the lifetime of this world, not
+    /// counting reserved entities until they are flushed.
+    pub fn peak_entity_count(&self) -> usize {
+        self.entities.peak_alive()
+    }
+
+    /// The most archetypes this world has had at once, including the empty archetype.
+    /// Unlike `archetype_creation_count` this is not increased by recreating archetypes
+    /// after `remove_empty_archetypes`.
+    pub fn peak_archetype_count(&self) -> usize {
+        self.peak_archetype_count
+    }
+
+    /// The archetype `entity` is in and its row in that archetype's columns, `None` if
+    /// the entity is dead.
+    pub fn debug_entity_row(&self, entity: Entity) -> Option<(usize, usize)> {
+        let archetype = self.entities.meta(entity)?.archetype;
+        let row = self.archetypes[archetype].get_entity_idx(entity).unwrap();
+        Some((archetype, row))
+    }
+
+    /// Removes every archetype with no entities other than the empty archetype, returning
+    /// how many were removed. Archetype indices of the remaining archetypes change so any
+    /// indices obtained from this world before calling this are invalidated.
+    pub fn remove_empty_archetypes(&mut self) -> usize {
+        let num_archetypes = self.archetypes.len();
+        let mut idx = 0;
+        self.archetypes.retain(|archetype| {
+            idx += 1;
+            idx == 1 || archetype.entities.is_empty() == false
+        });
+        let num_removed = num_archetypes - self.archetypes.len();
+        if num_removed == 0 {
+            return 0;
+        }
+        self.archetype_removal_count += 1;
+
+        // storages for each column are in archetype order after the template storage at
+        // index 0, so they can be repacked in the same order as the remaining archetypes.
+        let mut old_storages = self
+            .columns
+            .iter_mut()
+            .map(|(&id, storages)| {
+                let mut storages = std::mem::take(storages.get_mut())
+                    .into_iter()
+                    .map(Some)
+                    .collect::<Vec<_>>();
+                let template = storages[0].take().unwrap();
+                (id, (storages, vec![template]))
+            })
+            .collect::<HashMap<_, _>>();
+        for (archetype_idx, archetype) in self.archetypes.iter_mut().enumerate() {
+            for (id, column_idx) in archetype.column_indices.iter_mut() {
+                let (old, new) = old_storages.get_mut(id).unwrap();
+                new.push(old[*column_idx].take().unwrap());
+                *column_idx = new.len() - 1;
+            }
+            for &entity in &archetype.entities {
+                self.entities.meta_mut(entity).unwrap().archetype = archetype_idx;
+            }
+        }
+        for (id, (_, new)) in old_storages {
+            *self.columns.get_mut(&id).unwrap().get_mut() = new;
+        }
+        num_removed
+    }
+
+    /// The archetype with the most components, the empty archetype if no components
+    /// have been inserted.
+    pub fn widest_archetype(&self) -> &Archetype {
+        self.archetypes
+            .iter()
+            .max_by_key(|archetype| archetype.component_count())
+            .unwrap()
+    }
+
+    /// Reorders the entities of an archetype, and all of their components, by `compare`.
+    pub fn sort_archetype_by(
+        &mut self,
+        archetype: usize,
+        mut compare: impl FnMut(Entity, Entity) -> std::cmp::Ordering,
+    ) {
+        let archetype = &mut self.archetypes[archetype];
+        let mut permutation = (0..archetype.entities.len()).collect::<Vec<_>>();
+        permutation.sort_by(|&a, &b| compare(archetype.entities[a], archetype.entities[b]));
+        let entities = permutation
+            .iter()
+            .map(|&idx| archetype.entities[idx])
+            .collect();
+        archetype.set_entities(entities);
+        for (ty_id, &column_idx) in archetype.column_indices.iter() {
+            self.columns.get_mut(ty_id).unwrap().get_mut()[column_idx]
+                .apply_permutation(&permutation);
+        }
+    }
+
+    pub fn query<Q: query::QueryParam>(
+        &self,
+    ) -> Result<query::Query<'_, Q>, errors::WorldBorrowError> {
+        Ok(query::Query {
+            w: self,
+            locks: Q::lock_from_world(self)?.map(|lock| (lock, Vec::new())),
+            dyn_params: Vec::new(),
+            dyn_access: crate::Access::new(),
+        })
+    }
+
+    /// Builds a `Query<()>` with all of `params` added, errors if any of the params
+    /// conflict or their `EcsTypeId` is out of range for this world.
+    pub fn query_dynamic(
+        &self,
+        params: &[query::DynQueryParam],
+    ) -> Result<query::Query<'_, ()>, errors::WorldBorrowError> {
+        let mut query = self.query::<()>()?;
+        for &param in params {
+            query.try_add_dyn_param(param)?;
+        }
+        Ok(query)
     }
 
     pub fn access_scope<Out, Args, Func: crate::ToSystem<Args, Out>>(
@@ -525,6 +1618,19 @@ impl World {
         let mut system = system.system();
         system.run(self)
     }
+
+    /// Like `access_scope` but checks the system's access first, returning an error
+    /// instead of running a system whose params conflict with each other.
+    pub fn try_access_scope<Out, Args, Func: crate::ToSystem<Args, Out>>(
+        &mut self,
+        system: Func,
+    ) -> Result<Out, errors::AccessError> {
+        let mut system = system.system();
+        system
+            .get_access()
+            .map_err(|_| errors::AccessError(system.name().to_string()))?;
+        Ok(system.run(self))
+    }
 }
 
 fn get_two<T>(vec: &mut [T], idx_1: usize, idx_2: usize) -> (&mut T, &mut T) {
@@ -544,19 +1650,36 @@ fn get_two<T>(vec: &mut [T], idx_1: usize, idx_2: usize) -> (&mut T, &mut T) {
     }
 }
 
+/// Used when moving an entity between archetypes. Takes the archetypes rather than the
+/// world so that columns can be borrowed at the same time. Panics if `a == b`
+pub(crate) fn get_two_archetypes_mut(
+    archetypes: &mut [Archetype],
+    a: usize,
+    b: usize,
+) -> (&mut Archetype, &mut Archetype) {
+    assert!(a != b, "cannot get archetype {} mutably twice", a);
+    get_two(archetypes, a, b)
+}
+
 impl World {
-    fn get_column(&self, column_idx: usize, ecs_type_id: EcsTypeId) -> cell::Ref<'_, dyn Storage> {
-        cell::Ref::map(self.columns[&ecs_type_id].borrow(), |vec| &*vec[column_idx])
+    /// Returns `None` if the column is already mutably borrowed, e.g. by a `Query`
+    fn get_column(
+        &self,
+        column_idx: usize,
+        ecs_type_id: EcsTypeId,
+    ) -> Option<cell::Ref<'_, dyn Storage>> {
+        let columns = self.columns[&ecs_type_id].try_borrow().ok()?;
+        Some(cell::Ref::map(columns, |vec| &*vec[column_idx]))
     }
 
+    /// Returns `None` if the column is already borrowed, e.g. by a `Query`
     fn get_column_mut(
         &self,
         column_idx: usize,
         ecs_type_id: EcsTypeId,
-    ) -> cell::RefMut<'_, dyn Storage> {
-        cell::RefMut::map(self.columns[&ecs_type_id].borrow_mut(), |vec| {
-            &mut *vec[column_idx]
-        })
+    ) -> Option<cell::RefMut<'_, dyn Storage>> {
+        let columns = self.columns[&ecs_type_id].try_borrow_mut().ok()?;
+        Some(cell::RefMut::map(columns, |vec| &mut *vec[column_idx]))
     }
 
     fn find_archetype_from_ids(&self, ids: &[EcsTypeId]) -> Option<usize> {
@@ -586,14 +1709,7 @@ impl World {
             .map(|&type_id| type_id)
             .collect::<Vec<_>>();
 
-        self.find_archetype_from_ids(&new_type_ids)
-            .unwrap_or_else(|| {
-                let new_columns = new_type_ids
-                    .iter()
-                    .map(|type_id| self.columns[type_id].borrow()[0].empty_of_same_type())
-                    .collect();
-                self.push_archetype(new_type_ids, new_columns)
-            })
+        self.get_or_insert_archetype_from_ids(new_type_ids)
     }
 
     fn get_or_insert_archetype_from_insert(
@@ -613,14 +1729,17 @@ impl World {
             .chain(std::iter::once(inserted_ecs_type_id))
             .collect::<Vec<_>>();
 
-        self.find_archetype_from_ids(&new_type_ids)
-            .unwrap_or_else(|| {
-                let new_columns = new_type_ids
-                    .iter()
-                    .map(|type_id| self.columns[type_id].borrow()[0].empty_of_same_type())
-                    .collect();
-                self.push_archetype(new_type_ids, new_columns)
-            })
+        self.get_or_insert_archetype_from_ids(new_type_ids)
+    }
+
+    fn get_or_insert_archetype_from_ids(&mut self, ids: Vec<EcsTypeId>) -> usize {
+        self.find_archetype_from_ids(&ids).unwrap_or_else(|| {
+            let new_columns = ids
+                .iter()
+                .map(|type_id| self.columns[type_id].borrow()[0].empty_of_same_type())
+                .collect();
+            self.push_archetype(ids, new_columns)
+        })
     }
 
     fn push_archetype(
@@ -629,6 +1748,10 @@ impl World {
         storages: Vec<Box<dyn Storage>>,
     ) -> usize {
         assert!(self.find_archetype_from_ids(&type_ids).is_none());
+        assert!(
+            type_ids.iter().all(|id| !self.sparse_sets.contains_key(id)),
+            "components registered as sparse can't be stored in archetypes"
+        );
         let column_indices = type_ids
             .into_iter()
             .zip(storages.into_iter())
@@ -638,10 +1761,9 @@ impl World {
                 (type_id, columns.len() - 1)
             })
             .collect::<HashMap<_, _>>();
-        self.archetypes.push(Archetype {
-            entities: vec![],
-            column_indices,
-        });
+        self.archetypes.push(Archetype::new(vec![], column_indices));
+        self.archetype_creation_count += 1;
+        self.peak_archetype_count = usize::max(self.peak_archetype_count, self.archetypes.len());
         self.archetypes.len() - 1
     }
 }
@@ -666,6 +1788,10 @@ impl<'a> EntityBuilder<'a> {
     pub fn id(&self) -> Entity {
         self.entity
     }
+
+    pub fn despawn(self) {
+        self.world.despawn(self.entity);
+    }
 }
 
 #[cfg(test)]
@@ -730,150 +1856,1565 @@ mod tests {
     }
 
     #[test]
-    fn basic_remove() {
+    fn extend_components() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        world.remove_component::<u32>(e).unwrap_none();
-        world.insert_component(e, 10_u32).unwrap_none();
-        assert_eq!(world.remove_component::<u32>(e).unwrap(), 10_u32);
-        world.remove_component::<u32>(e).unwrap_none();
+        let e1 = world.spawn().insert(10_u32).id();
+        let e2 = world.spawn().id();
+        let e3 = world.spawn().insert(12_u64).id();
+        world.extend_components([(e1, 1_u32), (e2, 2_u32), (e3, 3_u32)]);
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 1_u32);
+        assert_eq!(*world.get_component::<u32>(e2).unwrap(), 2_u32);
+        assert_eq!(*world.get_component::<u32>(e3).unwrap(), 3_u32);
+        assert_eq!(*world.get_component::<u64>(e3).unwrap(), 12_u64);
     }
 
     #[test]
-    fn remove_archetype_change() {
+    fn for_each_mut() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        world.insert_component(e, 10_u32).unwrap_none();
-        world.insert_component(e, 12_u64).unwrap_none();
-        assert_eq!(world.insert_component(e, 15_u32).unwrap(), 10_u32);
-        world.remove_component::<u64>(e);
-        assert_eq!(*world.get_component::<u32>(e).unwrap(), 15_u32);
-        assert_eq!(world.has_component::<u64>(e).unwrap(), false);
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).insert(10_u64).id();
+        let e3 = world.spawn().insert(10_u64).id();
+        let mut visited = vec![];
+        world.for_each_mut::<u32>(|entity, component| {
+            visited.push(entity);
+            *component *= 2;
+        });
+        assert_eq!(visited, [e1, e2]);
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 2);
+        assert_eq!(*world.get_component::<u32>(e2).unwrap(), 4);
+        assert_eq!(*world.get_component::<u64>(e3).unwrap(), 10);
+        world.for_each_mut::<u128>(|_, _| unreachable!());
     }
 
     #[test]
-    fn remove_on_dead() {
+    fn drain_components() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        world.insert_component(e, 10_u32).unwrap_none();
-        world.despawn(e);
-        world.remove_component::<u32>(e).unwrap_none();
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).insert(10_u64).id();
+        let e3 = world.spawn().insert(10_u64).id();
+
+        let mut drained = world.drain_components::<u32>();
+        drained.sort_by_key(|&(_, value)| value);
+        assert_eq!(drained, [(e1, 1), (e2, 2)]);
+        assert_eq!(world.has_component::<u32>(e1), Some(false));
+        assert_eq!(world.has_component::<u32>(e2), Some(false));
+        assert_eq!(*world.get_component::<u64>(e2).unwrap(), 10);
+        assert_eq!(*world.get_component::<u64>(e3).unwrap(), 10);
+        assert_eq!(world.query::<&u32>().unwrap().iter_mut().count(), 0);
+        assert_eq!(world.drain_components::<u32>(), []);
+        assert_eq!(world.drain_components::<u128>(), []);
     }
-}
-
-#[cfg(test)]
-mod dynamic_tests {
-    use super::*;
-    use std::alloc::Layout;
 
-    trait UnwrapNone {
-        fn unwrap_none(self);
+    #[test]
+    fn reserve_entity() {
+        let mut world = World::new();
+        let e1 = world.reserve_entity();
+        let e2 = world.reserve_entity();
+        assert!(e1 != e2);
+        assert!(!world.is_alive(e1));
+        world.flush_reserved();
+        assert!(world.is_alive(e1) && world.is_alive(e2));
+        assert_eq!(world.has_component::<u32>(e1), Some(false));
+        world.entity_builder(e1).insert(10_u32);
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 10);
+        world.despawn(e2);
+        assert!(!world.is_alive(e2));
+        assert_eq!(world.query::<Entity>().unwrap().iter_mut().count(), 1);
     }
 
-    impl<T> UnwrapNone for Option<T> {
-        fn unwrap_none(self) {
-            match self {
-                Some(_) => panic!("expected `None` found `Some(_)`"),
-                None => (),
-            }
-        }
+    #[test]
+    fn is_reserved() {
+        let mut world = World::new();
+        let alive = world.spawn().id();
+        let dead = world.spawn().id();
+        world.despawn(dead);
+        let reserved = world.reserve_entity();
+
+        assert!(world.is_alive(alive) && !world.is_reserved(alive));
+        assert!(!world.is_alive(dead) && !world.is_reserved(dead));
+        assert!(!world.is_alive(reserved) && world.is_reserved(reserved));
+        assert!(!world.is_reserved(Entity::new(reserved.index() as u32 + 1, 0)));
+
+        world.flush_reserved();
+        assert!(world.is_alive(reserved) && !world.is_reserved(reserved));
     }
 
     #[test]
-    fn has_component_dynamic() {
+    fn reserved_entity_count() {
         let mut world = World::new();
+        world.spawn();
         let e = world.spawn().id();
-        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
-        assert_eq!(world.has_component_dynamic(e, id_u32).unwrap(), false);
+        world.despawn(e);
+        for _ in 0..3 {
+            world.reserve_entity();
+        }
+        assert_eq!(world.reserved_entity_count(), 3);
+        assert_eq!(world.materialized_entity_count(), 2);
+        world.flush_reserved();
+        assert_eq!(world.reserved_entity_count(), 0);
+        // the despawned entity's slot is reused by one of the reserved entities
+        assert_eq!(world.materialized_entity_count(), 4);
     }
 
     #[test]
-    fn basic_insert_dynamic() {
+    fn insert_into_reserved() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
-
-        world
-            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
-                *(ptr.1 as *mut u32) = 10;
-            })
-            .unwrap_none();
-
-        let (idx, storage) = world.get_component_mut_dynamic(e, id_u32).unwrap();
-        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) }, 10);
+        let e1 = world.reserve_entity();
+        let e2 = world.reserve_entity();
+        assert!(!world.is_alive(e1));
+        world.insert_component(e1, 10_u32);
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 10);
+        assert!(world.is_alive(e2));
+        assert_eq!(world.has_component::<u32>(e2), Some(false));
+        let e3 = world.spawn().id();
+        assert!(e3 != e1 && e3 != e2);
     }
 
     #[test]
-    fn insert_overwrite_dynamic() {
+    fn despawn_if() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).insert(10_u64).id();
+        let e3 = world.spawn().insert(10_u64).id();
+        let e4 = world.spawn().id();
 
-        world
-            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
-                *(ptr.1 as *mut u32) = 10;
-            })
-            .unwrap_none();
+        let u32_id = world.type_to_ecs_type_id::<u32>().unwrap();
+        assert_eq!(
+            world.despawn_if(|_, archetype| archetype.contains_component(u32_id)),
+            2
+        );
+        assert!(!world.is_alive(e1));
+        assert!(!world.is_alive(e2));
+        assert!(world.is_alive(e3));
+        assert!(world.is_alive(e4));
 
-        let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
-        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) }, 10);
+        assert_eq!(world.despawn_if(|entity, _| entity == e4), 1);
+        assert!(!world.is_alive(e4));
+        assert_eq!(world.despawn_if(|_, _| false), 0);
+        assert!(world.is_alive(e3));
     }
 
     #[test]
-    fn insert_archetype_change_dynamic() {
+    fn insert_with_many_components() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
-        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
-
-        world
-            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
-                *(ptr.1 as *mut u32) = 10;
-            })
-            .unwrap_none();
-        world
-            .insert_component_dynamic(e, id_u64, |ptr| unsafe {
-                *(ptr.1 as *mut u64) = 12;
+        let entities = (0..10_u8)
+            .map(|n| {
+                world
+                    .spawn()
+                    .insert(n)
+                    .insert(n as u16)
+                    .insert(n as u32)
+                    .insert(n as u64)
+                    .insert(n as u128)
+                    .id()
             })
-            .unwrap_none();
-
+            .collect::<Vec<_>>();
+        for &e in entities.iter().step_by(2) {
+            world.insert_component(e, true);
+        }
+        for (n, &e) in entities.iter().enumerate() {
+            assert_eq!(*world.get_component::<u8>(e).unwrap(), n as u8);
+            assert_eq!(*world.get_component::<u16>(e).unwrap(), n as u16);
+            assert_eq!(*world.get_component::<u32>(e).unwrap(), n as u32);
+            assert_eq!(*world.get_component::<u64>(e).unwrap(), n as u64);
+            assert_eq!(*world.get_component::<u128>(e).unwrap(), n as u128);
+            assert_eq!(world.has_component::<bool>(e), Some(n % 2 == 0));
+        }
+        for &e in entities.iter().step_by(2) {
+            assert_eq!(world.remove_component::<bool>(e), Some(true));
+        }
         assert_eq!(
-            unsafe {
-                *(world
-                    .insert_component_dynamic(e, id_u32, |ptr| {
-                        *(ptr.1 as *mut u32) = 15;
-                    })
-                    .unwrap()
-                    .1 as *const u32)
-            },
+            world.query::<(&u8, &u128)>().unwrap().iter_mut().count(),
             10
         );
+        assert_eq!(world.query::<&bool>().unwrap().iter_mut().count(), 0);
+    }
 
-        let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
-        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) }, 15);
-
-        let (idx, storage) = world.get_component_dynamic(e, id_u64).unwrap();
-        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u64) }, 12);
+    #[test]
+    fn archetype_creation_count() {
+        let mut world = World::new();
+        assert_eq!(world.archetype_creation_count(), 0);
+        let e1 = world.spawn().insert(1_u32).insert(1_u64).id();
+        assert_eq!(world.archetype_creation_count(), 2);
+        world.spawn().insert(2_u32).insert(2_u64);
+        assert_eq!(world.archetype_creation_count(), 2);
+        world.remove_component::<u32>(e1);
+        assert_eq!(world.archetype_creation_count(), 3);
+        world.remove_component::<u64>(e1);
+        world.insert_component(e1, 1_u32);
+        assert_eq!(world.archetype_creation_count(), 3);
+        world.despawn(e1);
+        assert_eq!(world.archetype_creation_count(), 3);
     }
 
     #[test]
-    fn insert_on_dead_dynamic() {
+    fn archetype_for() {
         let mut world = World::new();
-        let e = world.spawn().id();
-        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
-        world
-            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
-                *(ptr.1 as *mut u32) = 10;
-            })
-            .unwrap_none();
-        world.despawn(e);
-        world
-            .insert_component_dynamic(e, id_u32, |_| unreachable!(""))
-            .unwrap_none();
+        let e = world.spawn().insert(1_u32).insert(1_u64).id();
+        let u32_id = world.type_to_ecs_type_id::<u32>().unwrap();
+        let u64_id = world.type_to_ecs_type_id::<u64>().unwrap();
+        let bool_id = world.new_static_ecs_type_id::<bool>().unwrap();
+
+        assert_eq!(world.archetype_for(&[]), Some(0));
+        assert_eq!(world.archetype_for(&[u32_id]), Some(1));
+        let archetype = world.entities.meta(e).unwrap().archetype;
+        assert_eq!(world.archetype_for(&[u64_id, u32_id]), Some(archetype));
+        assert_eq!(world.archetype_for(&[u64_id]), None);
+        assert_eq!(world.archetype_for(&[u32_id, bool_id]), None);
+        assert_eq!(world.archetype_creation_count(), 2);
     }
 
     #[test]
-    fn basic_remove_dynamic() {
+    fn archetypes_containing() {
+        let mut world = World::new();
+        world.spawn().insert(1_u32);
+        world.spawn().insert(1_u32).insert(1_u64);
+        world.spawn().insert(1_u64);
+        world.spawn().insert(true);
+        let u32_id = world.type_to_ecs_type_id::<u32>().unwrap();
+
+        let archetypes = world
+            .archetypes_containing(u32_id)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        assert_eq!(archetypes, [1, 2]);
+        let mut matched = vec![];
+        let mut q = world.query::<&u32>().unwrap();
+        for iter in q.iter_by_archetype_mut() {
+            matched.push(iter.count());
+        }
+        let entity_counts = world
+            .archetypes_containing(u32_id)
+            .map(|(_, archetype)| archetype.entities().len())
+            .collect::<Vec<_>>();
+        assert_eq!(matched, entity_counts);
+    }
+
+    #[test]
+    fn widest_archetype() {
+        let mut world = World::new();
+        assert_eq!(world.widest_archetype().component_count(), 0);
+        world.spawn().insert(1_u32);
+        let e = world.spawn().insert(1_u32).insert(1_u64).insert(true).id();
+        world.spawn().insert(1_u64).insert(1_u128);
+        assert_eq!(world.widest_archetype().component_count(), 3);
+        assert_eq!(world.widest_archetype().entities(), &[e]);
+        assert_eq!(world.archetypes[1].component_count(), 1);
+    }
+
+    #[test]
+    fn entity_indices_in_sync() {
+        let mut world = World::new();
+        let entities = (0..20_u32)
+            .map(|n| world.spawn().insert(n).id())
+            .collect::<Vec<_>>();
+        for &e in entities.iter().step_by(3) {
+            world.insert_component(e, true);
+        }
+        for &e in entities.iter().step_by(4) {
+            world.despawn(e);
+        }
+        for &e in entities.iter().step_by(5) {
+            world.remove_component::<u32>(e);
+        }
+        world.sort_archetype_by(1, |a, b| b.cmp(&a));
+
+        for archetype in &world.archetypes {
+            assert_eq!(archetype.entity_indices.len(), archetype.entities.len());
+            for (idx, &entity) in archetype.entities.iter().enumerate() {
+                assert_eq!(archetype.get_entity_idx(entity), Some(idx));
+            }
+        }
+        for &e in entities.iter().filter(|e| world.is_alive(**e)) {
+            if e.index() % 5 != 0 {
+                assert_eq!(*world.get_component::<u32>(e).unwrap(), e.index() as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn debug_entity_row() {
+        let mut world = World::new();
+        let entities = (0..6_u32)
+            .map(|n| world.spawn().insert(n).id())
+            .collect::<Vec<_>>();
+        world.despawn(entities[1]);
+        world.insert_component(entities[2], true);
+        assert_eq!(world.debug_entity_row(entities[1]), None);
+
+        let u32_id = world.type_to_ecs_type_id::<u32>().unwrap();
+        for &e in entities.iter().filter(|e| world.is_alive(**e)) {
+            let (archetype, row) = world.debug_entity_row(e).unwrap();
+            assert_eq!(world.archetypes[archetype].entities()[row], e);
+            let column_idx = world.archetypes[archetype].column_indices[&u32_id];
+            let column = world.columns[&u32_id].borrow();
+            let column = column[column_idx]
+                .as_typed_storage()
+                .unwrap()
+                .as_vec::<u32>()
+                .unwrap();
+            assert_eq!(column[row], e.index() as u32);
+        }
+    }
+
+    #[test]
+    fn get_two_archetypes_mut_moves_entity() {
+        let [e1, e2, e3] = [0, 1, 2].map(|idx| Entity::new(idx, 0));
+        let mut archetypes = vec![
+            Archetype::new(vec![e1, e2], HashMap::new()),
+            Archetype::new(vec![e3], HashMap::new()),
+        ];
+        let (from, to) = get_two_archetypes_mut(&mut archetypes, 0, 1);
+        let moved = from.swap_remove_entity(0);
+        to.push_entity(moved);
+        let (to, from) = get_two_archetypes_mut(&mut archetypes, 1, 0);
+        assert_eq!(from.entities(), &[e2]);
+        assert_eq!(to.entities(), &[e3, e1]);
+        assert_eq!(to.get_entity_idx(e1), Some(1));
+        assert_eq!(from.get_entity_idx(e2), Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot get archetype 1 mutably twice")]
+    fn get_two_archetypes_mut_same() {
+        let mut world = World::new();
+        world.spawn().insert(10_u32);
+        get_two_archetypes_mut(&mut world.archetypes, 1, 1);
+    }
+
+    #[test]
+    fn map_component() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(1_u32).id();
+        let e2 = world.spawn().insert(2_u32).insert(true).id();
+        let e3 = world.spawn().insert(true).id();
+
+        world.map_component::<u32, u64>(|value| value as u64 * 10);
+        assert_eq!(world.query::<&u32>().unwrap().iter_mut().count(), 0);
+        assert_eq!(*world.get_component::<u64>(e1).unwrap(), 10);
+        assert_eq!(*world.get_component::<u64>(e2).unwrap(), 20);
+        assert_eq!(*world.get_component::<bool>(e2).unwrap(), true);
+        assert_eq!(world.has_component::<u64>(e3), Some(false));
+    }
+
+    #[test]
+    fn despawn_never_spawned() {
+        let mut world = World::new();
+        let e = world.spawn().insert(10_u32).id();
+        world.despawn(Entity::new(1_000_000, 0));
+        world.despawn(Entity::new(u32::MAX, 0));
+        assert_eq!(world.materialized_entity_count(), 1);
+        assert!(world.is_alive(e));
+        assert_eq!(*world.get_component::<u32>(e).unwrap(), 10);
+    }
+
+    #[test]
+    fn builder_scope() {
+        use crate::Scope;
+        let mut world = World::new();
+        let e = world
+            .spawn()
+            .scope_mut(|builder| {
+                builder.insert(10_u32);
+                builder.insert(12_u64);
+            })
+            .id();
+        assert_eq!(*world.get_component::<u32>(e).unwrap(), 10);
+        assert_eq!(*world.get_component::<u64>(e).unwrap(), 12);
+    }
+
+    #[test]
+    fn spawn_batch_with() {
+        let mut world = World::new();
+        let other = world.spawn().insert(10_u32).id();
+        let entities = world.spawn_batch_with(1000, |i| i as u32);
+        assert_eq!(entities.len(), 1000);
+
+        let archetype = world.entities.meta(entities[0]).unwrap().archetype;
+        for (i, &entity) in entities.iter().enumerate() {
+            assert_eq!(world.entities.meta(entity).unwrap().archetype, archetype);
+            assert_eq!(*world.get_component::<u32>(entity).unwrap(), i as u32);
+        }
+        assert_eq!(world.entities.meta(other).unwrap().archetype, archetype);
+        assert_eq!(*world.get_component::<u32>(other).unwrap(), 10);
+        assert_eq!(world.archetypes[archetype].entities.len(), 1001);
+        assert_eq!(world.archetypes[0].entities.len(), 0);
+
+        assert_eq!(world.spawn_batch_with(0, |i| i as u32), vec![]);
+    }
+
+    #[test]
+    fn spawn_batch_with_panic() {
+        let mut world = World::new();
+        let e = world.spawn().insert(10_u32).id();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            world.spawn_batch_with(10, |i| if i == 5 { panic!("oops") } else { i as u32 });
+        }));
+        assert!(result.is_err());
+
+        let archetype = world.entities.meta(e).unwrap().archetype;
+        assert_eq!(world.archetypes[archetype].entities.len(), 1);
+        assert_eq!(
+            world.len_in_archetype(world.type_to_ecs_type_id::<u32>().unwrap(), archetype),
+            Some(1)
+        );
+        assert_eq!(world.iter_entities_sorted().count(), 1);
+
+        let entities = world.spawn_batch_with(3, |i| i as u32);
+        world.despawn(e);
+        for (i, &entity) in entities.iter().enumerate() {
+            assert_eq!(*world.get_component::<u32>(entity).unwrap(), i as u32);
+        }
+    }
+
+    #[test]
+    fn spawn_each() {
+        let mut world = World::new();
+        let entities = world.spawn_each(vec![10_u32, 12, 14]);
+        assert_eq!(entities.len(), 3);
+        for (entity, value) in entities.into_iter().zip([10_u32, 12, 14]) {
+            assert_eq!(*world.get_component::<u32>(entity).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn upsert_component() {
+        let mut world = World::new();
+        let e1 = world.spawn().id();
+        world.upsert_component(e1, || 10_u32, |_| unreachable!());
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 10);
+        world.upsert_component(e1, || -> u32 { unreachable!() }, |value| *value += 2);
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 12);
+
+        world.despawn(e1);
+        world.upsert_component(e1, || -> u32 { unreachable!() }, |_| unreachable!());
+        assert!(world.get_component::<u32>(e1).is_none());
+    }
+
+    #[test]
+    fn iter_entities_sorted() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(10_u32).id();
+        let e2 = world.spawn().id();
+        let e3 = world.spawn().insert(10_u64).id();
+        let e4 = world.spawn().insert(10_u32).id();
+        world.despawn(e2);
+        world.reserve_entity();
+
+        let sorted = world.iter_entities_sorted().collect::<Vec<_>>();
+        assert_eq!(sorted, vec![e1, e3, e4]);
+        let mut unsorted = world
+            .archetypes
+            .iter()
+            .flat_map(|archetype| archetype.entities().iter().copied())
+            .collect::<Vec<_>>();
+        assert_ne!(unsorted, sorted);
+        unsorted.sort();
+        assert_eq!(unsorted, sorted);
+    }
+
+    #[test]
+    fn remove_empty_archetypes() {
+        let mut world = World::new();
+        let mut alive = vec![];
+        for n in 0..20_u32 {
+            let e = world.spawn().insert(n).id();
+            match n % 4 {
+                0 => {
+                    world.insert_component(e, n as u64);
+                }
+                1 => {
+                    world.entity_builder(e).insert(n as u8).insert(true);
+                }
+                2 => {
+                    world.insert_component(e, n as u16);
+                    world.remove_component::<u32>(e);
+                }
+                _ => (),
+            }
+            if n % 4 == 1 {
+                world.despawn(e);
+            } else {
+                alive.push((e, n));
+            }
+        }
+        let churned = world.spawn().insert(1_u128).id();
+        world.remove_component::<u128>(churned);
+
+        let num_archetypes = world.archetypes.len();
+        let num_removed = world.remove_empty_archetypes();
+        assert!(num_removed > 0);
+        assert_eq!(world.archetypes.len(), num_archetypes - num_removed);
+        assert_eq!(world.remove_empty_archetypes(), 0);
+        assert!(world.archetypes[1..]
+            .iter()
+            .all(|archetype| archetype.entities.is_empty() == false));
+
+        for (idx, archetype) in world.archetypes.iter().enumerate() {
+            for (id, &column) in archetype.column_indices.iter() {
+                assert_eq!(
+                    world.len_in_archetype(*id, idx),
+                    Some(archetype.entities.len())
+                );
+                assert!(column != 0);
+            }
+        }
+        let mut found = world
+            .query::<Entity>()
+            .unwrap()
+            .iter_mut()
+            .collect::<Vec<_>>();
+        found.sort();
+        let mut expected = alive.iter().map(|&(e, _)| e).collect::<Vec<_>>();
+        expected.push(churned);
+        expected.sort();
+        assert_eq!(found, expected);
+        for &(e, n) in &alive {
+            match n % 4 {
+                0 => assert_eq!(*world.get_component::<u64>(e).unwrap(), n as u64),
+                2 => assert_eq!(*world.get_component::<u16>(e).unwrap(), n as u16),
+                _ => (),
+            }
+            if n % 4 != 2 {
+                assert_eq!(*world.get_component::<u32>(e).unwrap(), n);
+            }
+        }
+
+        // archetypes can still be created and found afterwards
+        let e = world.spawn().insert(1_u8).id();
+        world.insert_component(e, true);
+        let e2 = world.spawn().insert(2_u64).insert(2_u32).id();
+        assert_eq!(
+            world.entities.meta(e2).unwrap().archetype,
+            world.entities.meta(alive[0].0).unwrap().archetype
+        );
+        assert_eq!(*world.get_component::<bool>(e).unwrap(), true);
+    }
+
+    #[test]
+    fn clear() {
+        use std::{cell::RefCell, rc::Rc};
+        let mut world = World::new();
+        let removed = Rc::new(RefCell::new(vec![]));
+        let removed_2 = removed.clone();
+        let u32_id = world.type_to_ecs_type_id_or_create::<u32>();
+        world.on_remove(u32_id, move |entity| removed_2.borrow_mut().push(entity));
+        let u64_id = world.register_component::<u64>(StorageKind::Sparse);
+        let removed_2 = removed.clone();
+        world.on_remove(u64_id, move |entity| removed_2.borrow_mut().push(entity));
+
+        let e1 = world.spawn().insert(10_u32).id();
+        let e2 = world.spawn().insert(11_u32).insert(true).id();
+        let e3 = world.spawn().insert(13_u64).id();
+        let reserved = world.reserve_entity();
+        world.clear();
+
+        let mut removed = removed.borrow().clone();
+        removed.sort();
+        assert_eq!(removed, [e1, e2, e3]);
+        assert_eq!(world.archetypes.len(), 1);
+        assert!(world.sparse_set::<u64>().unwrap().is_empty());
+        for e in [e1, e2, e3, reserved] {
+            assert!(!world.is_alive(e));
+        }
+        assert_eq!(world.query::<Entity>().unwrap().iter_mut().count(), 0);
+        assert_eq!(world.query::<&u32>().unwrap().iter_mut().count(), 0);
+        assert_eq!(world.columns[&u32_id].borrow().len(), 1);
+
+        // reused slots don't bring the old entities back to life
+        let e4 = world.spawn().insert(12_u32).id();
+        assert!([e1, e2, e3, reserved].iter().any(|e| e.same_index(e4)));
+        for e in [e1, e2, e3, reserved] {
+            assert!(!world.is_alive(e));
+        }
+        let mut q = world.query::<(Entity, &u32)>().unwrap();
+        assert_eq!(q.iter_mut().collect::<Vec<_>>(), [(e4, &12)]);
+    }
+
+    #[test]
+    fn get_components() {
+        let mut world = World::new();
+        let e1 = world.spawn().insert(10_u32).id();
+        let e2 = world.spawn().insert(true).id();
+        let e3 = world.spawn().insert(12_u32).insert(true).id();
+        let dead = world.spawn().insert(14_u32).id();
+        world.despawn(dead);
+
+        let entities = [e3, e2, dead, e1];
+        let found = world
+            .get_components::<u32>(&entities)
+            .map(|value| value.map(|value| *value))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [Some(12), None, None, Some(10)]);
+        assert_eq!(world.get_components::<u64>(&entities).flatten().count(), 0);
+    }
+
+    #[test]
+    fn peak_counts() {
+        let mut world = World::new();
+        assert_eq!(
+            (world.peak_entity_count(), world.peak_archetype_count()),
+            (0, 1)
+        );
+        let entities = (0..10).map(|_| world.spawn().id()).collect::<Vec<_>>();
+        for &e in &entities[..5] {
+            world.despawn(e);
+        }
+        world.spawn();
+        world.despawn(entities[5]);
+        assert_eq!(world.peak_entity_count(), 10);
+        world.spawn_batch_with(6, |i| i as u32);
+        assert_eq!(world.peak_entity_count(), 11);
+        world.reserve_entity();
+        assert_eq!(world.peak_entity_count(), 11);
+        world.flush_reserved();
+        assert_eq!(world.peak_entity_count(), 12);
+
+        let e = world.spawn().insert(true).insert(1_u8).id();
+        assert_eq!(world.peak_archetype_count(), 4);
+        world.despawn(e);
+        world.remove_empty_archetypes();
+        assert_eq!(world.archetypes.len(), 2);
+        world.spawn().insert(true);
+        assert_eq!(world.peak_archetype_count(), 4);
+    }
+
+    #[test]
+    fn storage_truncate() {
+        use std::{cell::Cell, rc::Rc};
+        struct Dropped(Rc<Cell<u32>>);
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+        impl Component for Dropped {}
+
+        let drops = Rc::new(Cell::new(0));
+        let mut storage: Box<dyn Storage> =
+            Box::new((0..5).map(|_| Dropped(drops.clone())).collect::<Vec<_>>());
+        storage.truncate(8);
+        assert_eq!((storage.num_elements(), drops.get()), (5, 0));
+        storage.truncate(2);
+        assert_eq!((storage.num_elements(), drops.get()), (2, 3));
+        drop(storage);
+        assert_eq!(drops.get(), 5);
+    }
+
+    #[test]
+    fn insert_shared_component() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Tag(Vec<u32>);
+        impl Component for Tag {}
+
+        let mut world = World::new();
+        let mut entities = (0..50_u32)
+            .map(|n| match n % 3 {
+                0 => world.spawn().id(),
+                1 => world.spawn().insert(n).id(),
+                _ => world.spawn().insert(n).insert(n as u64).id(),
+            })
+            .collect::<Vec<_>>();
+        world.insert_component(entities[0], Tag(vec![]));
+        let unlisted = world.spawn().insert(1_u32).id();
+        entities.push(entities[1]);
+
+        let archetypes_before = world.archetype_creation_count();
+        world.insert_shared_component(&entities, Tag(vec![1, 2]));
+        assert_eq!(world.archetype_creation_count(), archetypes_before + 2);
+
+        for &entity in &entities {
+            assert_eq!(
+                *world.get_component::<Tag>(entity).unwrap(),
+                Tag(vec![1, 2])
+            );
+        }
+        for (n, &entity) in entities[..50].iter().enumerate() {
+            if n % 3 != 0 {
+                assert_eq!(*world.get_component::<u32>(entity).unwrap(), n as u32);
+            }
+        }
+        assert_eq!(world.has_component::<Tag>(unlisted), Some(false));
+        assert_eq!(world.query::<&Tag>().unwrap().iter_mut().count(), 50);
+
+        world
+            .get_component_mut::<Tag>(entities[0])
+            .unwrap()
+            .0
+            .push(3);
+        assert_eq!(
+            *world.get_component::<Tag>(entities[1]).unwrap(),
+            Tag(vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn builder_despawn() {
+        let mut world = World::new();
+        let mut builder = world.spawn();
+        builder.insert(10_u32).insert(12_u64);
+        let e = builder.id();
+        builder.despawn();
+        assert!(!world.is_alive(e));
+        assert!(world.archetypes.iter().all(|a| a.entities.is_empty()));
+        assert_eq!(world.query::<&u32>().unwrap().iter_mut().next(), None);
+    }
+
+    #[test]
+    fn with_capacity() {
+        let mut world = World::with_capacity(100);
+        let meta_capacity = world.entities.meta.capacity();
+        let archetype_capacity = world.archetypes[0].entities.capacity();
+        assert!(meta_capacity >= 100 && archetype_capacity >= 100);
+        for _ in 0..100 {
+            world.spawn_empty();
+        }
+        assert_eq!(world.entities.meta.capacity(), meta_capacity);
+        assert_eq!(world.archetypes[0].entities.capacity(), archetype_capacity);
+        assert_eq!(world.archetypes[0].entities.len(), 100);
+    }
+
+    #[test]
+    fn spawn_empty() {
+        let mut world = World::new();
+        let entities = (0..100).map(|_| world.spawn_empty()).collect::<Vec<_>>();
+        assert!(entities.iter().all(|&e| world.is_alive(e)));
+        assert_eq!(world.archetypes[0].entities, entities);
+    }
+
+    #[test]
+    fn despawn_empty_entities() {
+        let mut world = World::new();
+        let entities = (0..1000).map(|_| world.spawn().id()).collect::<Vec<_>>();
+        assert_eq!(world.archetypes[0].entities.len(), 1000);
+        for (n, &e) in entities.iter().enumerate() {
+            world.despawn(e);
+            assert_eq!(world.archetypes[0].entities.len(), 999 - n);
+        }
+        assert!(world.archetypes[0].entities.is_empty());
+        assert!(entities.iter().all(|&e| !world.is_alive(e)));
+        assert!(entities.iter().all(|&e| world.entities.meta(e).is_none()));
+    }
+
+    #[test]
+    fn get_many_components_mut() {
+        let mut world = World::new();
+        let e = world
+            .spawn()
+            .insert(1_u32)
+            .insert(2_u64)
+            .insert(3_u128)
+            .id();
+        {
+            let mut a = world.get_component_mut::<u32>(e).unwrap();
+            let mut b = world.get_component_mut::<u64>(e).unwrap();
+            let mut c = world.get_component_mut::<u128>(e).unwrap();
+            *a += 10;
+            *b += 10;
+            *c += 10;
+        }
+        assert_eq!(*world.get_component::<u32>(e).unwrap(), 11);
+        assert_eq!(*world.get_component::<u64>(e).unwrap(), 12);
+        assert_eq!(*world.get_component::<u128>(e).unwrap(), 13);
+    }
+
+    #[test]
+    fn get_component_while_queried() {
+        let mut world = World::new();
+        let e = world.spawn().insert(10_u32).id();
+
+        let q = world.query::<&u32>().unwrap();
+        assert_eq!(*world.get_component::<u32>(e).unwrap(), 10_u32);
+        assert!(world.get_component_mut::<u32>(e).is_none());
+        drop(q);
+
+        let q = world.query::<&mut u32>().unwrap();
+        assert!(world.get_component::<u32>(e).is_none());
+        assert!(world.get_component_mut::<u32>(e).is_none());
+        drop(q);
+
+        assert_eq!(*world.get_component_mut::<u32>(e).unwrap(), 10_u32);
+    }
+
+    #[test]
+    fn basic_remove() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        world.remove_component::<u32>(e).unwrap_none();
+        world.insert_component(e, 10_u32).unwrap_none();
+        assert_eq!(world.remove_component::<u32>(e).unwrap(), 10_u32);
+        world.remove_component::<u32>(e).unwrap_none();
+    }
+
+    #[test]
+    fn remove_archetype_change() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        world.insert_component(e, 10_u32).unwrap_none();
+        world.insert_component(e, 12_u64).unwrap_none();
+        assert_eq!(world.insert_component(e, 15_u32).unwrap(), 10_u32);
+        world.remove_component::<u64>(e);
+        assert_eq!(*world.get_component::<u32>(e).unwrap(), 15_u32);
+        assert_eq!(world.has_component::<u64>(e).unwrap(), false);
+    }
+
+    #[test]
+    fn on_remove_hook() {
+        use std::{cell::Cell, rc::Rc};
+
+        let mut world = World::new();
+        let id_u32 = world.type_to_ecs_type_id_or_create::<u32>();
+        let removed = Rc::new(Cell::new(0));
+        let counter = removed.clone();
+        world.on_remove(id_u32, move |_| counter.set(counter.get() + 1));
+
+        let e1 = world.spawn().insert(10_u32).insert(12_u64).id();
+        let e2 = world.spawn().insert(10_u32).id();
+        let e3 = world.spawn().insert(12_u64).id();
+        world.despawn(e1);
+        world.despawn(e3);
+        assert_eq!(removed.get(), 1);
+        world.remove_component::<u32>(e2).unwrap();
+        assert_eq!(removed.get(), 2);
+        world.despawn(e2);
+        assert_eq!(removed.get(), 2);
+    }
+
+    #[test]
+    fn on_insert_hook() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let mut world = World::new();
+        let id_u32 = world.type_to_ecs_type_id_or_create::<u32>();
+        let inserts = Rc::new(RefCell::new(vec![]));
+        let log = inserts.clone();
+        world.on_insert(id_u32, move |e, overwrite| {
+            log.borrow_mut().push((e, overwrite))
+        });
+
+        let e1 = world.spawn().insert(10_u32).id();
+        let e2 = world.spawn().insert(12_u64).id();
+        world.insert_component(e1, 11_u32).unwrap();
+        world.insert_component(e2, 13_u32).unwrap_none();
+        assert_eq!(
+            inserts.borrow().as_slice(),
+            &[(e1, false), (e1, true), (e2, false)]
+        );
+    }
+
+    #[test]
+    fn remove_on_dead() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        world.insert_component(e, 10_u32).unwrap_none();
+        world.despawn(e);
+        world.remove_component::<u32>(e).unwrap_none();
+    }
+
+    #[test]
+    fn sparse_components() {
+        let mut world = World::new();
+        let id = world.register_component::<u64>(StorageKind::Sparse);
+        assert_eq!(world.register_component::<u64>(StorageKind::Sparse), id);
+        assert_eq!(world.storage_kind(id), StorageKind::Sparse);
+        let u32_id = world.type_to_ecs_type_id_or_create::<u32>();
+        assert_eq!(world.storage_kind(u32_id), StorageKind::Table);
+
+        let e1 = world.spawn().insert(10_u32).id();
+        let e2 = world.spawn().insert(12_u32).id();
+        let archetype = world.entities.meta(e1).unwrap().archetype;
+        let num_archetypes = world.archetypes.len();
+        world.insert_component(e1, 1_u64).unwrap_none();
+        world.insert_component(e2, 2_u64).unwrap_none();
+        assert_eq!(world.insert_component(e1, 3_u64), Some(1));
+        assert_eq!(world.entities.meta(e1).unwrap().archetype, archetype);
+        assert_eq!(world.archetypes.len(), num_archetypes);
+
+        assert_eq!(world.has_component::<u64>(e1), Some(true));
+        assert_eq!(*world.get_component::<u64>(e1).unwrap(), 3);
+        *world.get_component_mut::<u64>(e2).unwrap() += 1;
+        assert_eq!(*world.get_component::<u64>(e2).unwrap(), 3);
+        {
+            let _set = world.sparse_set::<u64>().unwrap();
+            assert!(world.get_component_mut::<u64>(e1).is_none());
+        }
+
+        assert_eq!(world.remove_component::<u64>(e1), Some(3));
+        world.remove_component::<u64>(e1).unwrap_none();
+        assert_eq!(world.has_component::<u64>(e1), Some(false));
+        assert_eq!(*world.get_component::<u32>(e1).unwrap(), 10);
+
+        world.despawn(e2);
+        assert_eq!(world.has_component::<u64>(e2), None);
+        world.insert_component(e2, 5_u64).unwrap_none();
+        assert!(world.sparse_set::<u64>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sparse_bulk_methods() {
+        use std::{cell::Cell, rc::Rc};
+
+        let mut world = World::new();
+        let id = world.register_component::<u64>(StorageKind::Sparse);
+        let hook_calls = Rc::new(Cell::new(0));
+        let calls = hook_calls.clone();
+        world.on_insert(id, move |_, _| calls.set(calls.get() + 1));
+        let calls = hook_calls.clone();
+        world.on_remove(id, move |_| calls.set(calls.get() + 100));
+
+        let batch = world.spawn_batch_with(2, |i| i as u64);
+        let each = world.spawn_each([5_u64, 6]);
+        assert_eq!(world.archetypes.len(), 1);
+        assert_eq!(hook_calls.get(), 4);
+
+        let plain = world.spawn().id();
+        world.insert_shared_component(&[plain, batch[0]], 9_u64);
+        assert_eq!(*world.get_component::<u64>(plain).unwrap(), 9);
+        assert_eq!(*world.get_component::<u64>(batch[0]).unwrap(), 9);
+
+        world.for_each_mut::<u64>(|_, n| *n += 1);
+        assert_eq!(*world.get_component::<u64>(each[1]).unwrap(), 7);
+
+        world.map_component::<u64, u32>(|n| n as u32);
+        assert!(world.sparse_set::<u64>().unwrap().is_empty());
+        assert_eq!(*world.get_component::<u32>(batch[1]).unwrap(), 2);
+        assert_eq!(*world.get_component::<u32>(plain).unwrap(), 10);
+
+        world.insert_component(each[0], 1_u64).unwrap_none();
+        hook_calls.set(0);
+        world.despawn(each[0]);
+        world.despawn(each[1]);
+        assert_eq!(hook_calls.get(), 100);
+        assert_eq!(world.drain_components::<u64>(), []);
+    }
+
+    #[test]
+    #[should_panic(expected = "is already stored in archetypes")]
+    fn register_sparse_after_table_insert() {
+        let mut world = World::new();
+        world.spawn().insert(10_u64);
+        world.register_component::<u64>(StorageKind::Sparse);
+    }
+}
+
+#[cfg(test)]
+mod dynamic_tests {
+    use super::*;
+    use crate::DynQueryParam;
+    use std::alloc::Layout;
+
+    trait UnwrapNone {
+        fn unwrap_none(self);
+    }
+
+    impl<T> UnwrapNone for Option<T> {
+        fn unwrap_none(self) {
+            match self {
+                Some(_) => panic!("expected `None` found `Some(_)`"),
+                None => (),
+            }
+        }
+    }
+
+    #[test]
+    fn has_component_dynamic() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        assert_eq!(world.has_component_dynamic(e, id_u32).unwrap(), false);
+    }
+
+    #[test]
+    fn insert_into_reserved_dynamic() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e = world.reserve_entity();
+        world
+            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 10;
+            })
+            .unwrap_none();
+        assert_eq!(world.has_component_dynamic(e, id_u32), Some(true));
+    }
+
+    #[test]
+    fn insert_dynamic_in_place() {
+        let mut world = World::new();
+        let id_u128 = world.new_dynamic_ecs_type_id(Layout::new::<u128>());
+        let e = world.spawn().id();
+        world
+            .insert_component_dynamic(e, id_u128, |ptr| {
+                assert_eq!(ptr.1.len(), 16);
+                assert_eq!(ptr.1 as *mut u8 as usize % std::mem::align_of::<u128>(), 0);
+                unsafe { *(ptr.1 as *mut u128) = u128::MAX - 1 };
+            })
+            .unwrap_none();
+        let (idx, storage) = world.get_component_dynamic(e, id_u128).unwrap();
+        assert_eq!(
+            unsafe { *(storage.get_element_ptr(idx).1 as *const u128) },
+            u128::MAX - 1
+        );
+    }
+
+    #[test]
+    fn move_component_dynamic() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let read = |world: &World, e| {
+            let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
+            unsafe { *(storage.get_element_ptr(idx).1 as *const u32) }
+        };
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        let e3 = world.spawn().id();
+        for (e, value) in [(e1, 10_u32), (e3, 30_u32)] {
+            world
+                .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                    *(ptr.1 as *mut u32) = value;
+                })
+                .unwrap_none();
+        }
+
+        assert!(world.move_component_dynamic(id_u32, e1, e2));
+        assert_eq!(world.has_component_dynamic(e1, id_u32), Some(false));
+        assert_eq!(read(&world, e2), 10);
+
+        // overwrites
+        assert!(world.move_component_dynamic(id_u32, e3, e2));
+        assert_eq!(world.has_component_dynamic(e3, id_u32), Some(false));
+        assert_eq!(read(&world, e2), 30);
+
+        assert!(!world.move_component_dynamic(id_u32, e1, e3));
+        assert!(!world.move_component_dynamic(id_u32, e2, e2));
+        world.despawn(e3);
+        assert!(!world.move_component_dynamic(id_u32, e2, e3));
+        assert_eq!(read(&world, e2), 30);
+    }
+
+    #[test]
+    fn insert_dynamic_panic() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        for e in [e1, e2] {
+            world
+                .insert_component_dynamic(e, id_u64, |ptr| unsafe {
+                    *(ptr.1 as *mut u64) = 10;
+                })
+                .unwrap_none();
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            world.insert_component_dynamic(e1, id_u32, |_| panic!("oops"));
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(world.has_component_dynamic(e1, id_u32), Some(false));
+        assert_eq!(world.has_component_dynamic(e1, id_u64), Some(true));
+        for archetype in world.archetypes.iter() {
+            for (id, &column_idx) in archetype.column_indices.iter() {
+                let columns = world.columns[id].borrow();
+                let storage = columns[column_idx].as_erased_storage().unwrap();
+                assert_eq!(storage.num_elements(), archetype.entities.len());
+            }
+        }
+
+        world
+            .insert_component_dynamic(e2, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 12;
+            })
+            .unwrap_none();
+        let (idx, storage) = world.get_component_dynamic(e2, id_u32).unwrap();
+        assert_eq!(
+            unsafe { *(storage.get_element_ptr(idx).1 as *const u32) },
+            12
+        );
+        drop(storage);
+        let mut q = world.query::<Entity>().unwrap();
+        q.add_dyn_param(DynQueryParam::new_ref(id_u64));
+        assert_eq!(q.iter_mut().count(), 2);
+    }
+
+    #[test]
+    fn iter_dynamic_mut() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        let e3 = world.spawn().id();
+        for (e, value) in [(e1, 1_u32), (e2, 2_u32)] {
+            world
+                .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                    *(ptr.1 as *mut u32) = value;
+                })
+                .unwrap_none();
+        }
+        for e in [e2, e3] {
+            world
+                .insert_component_dynamic(e, id_u64, |ptr| unsafe {
+                    *(ptr.1 as *mut u64) = 10;
+                })
+                .unwrap_none();
+        }
+
+        let mut visited = vec![];
+        for (entity, ptr) in world.iter_dynamic_mut(id_u32) {
+            assert_eq!(ptr.1.len(), 4);
+            unsafe { *(ptr.1 as *mut u32) *= 10 };
+            visited.push(entity);
+        }
+        visited.sort();
+        assert_eq!(visited, [e1, e2]);
+        for (e, value) in [(e1, 10_u32), (e2, 20_u32)] {
+            let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
+            assert_eq!(
+                unsafe { *(storage.get_element_ptr(idx).1 as *const u32) },
+                value
+            );
+        }
+
+        let unused_id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        assert_eq!(world.iter_dynamic_mut(unused_id).count(), 0);
+    }
+
+    #[test]
+    fn copy_component_dynamic() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let read = |world: &World, e| {
+            let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
+            unsafe { *(storage.get_element_ptr(idx).1 as *const u32) }
+        };
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        let e3 = world.spawn().id();
+        world
+            .insert_component_dynamic(e1, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 10;
+            })
+            .unwrap_none();
+
+        assert!(world.copy_component_dynamic(id_u32, e1, e2));
+        assert_eq!(read(&world, e1), 10);
+        assert_eq!(read(&world, e2), 10);
+
+        let (idx, storage) = world.get_component_mut_dynamic(e2, id_u32).unwrap();
+        unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) = 20 };
+        drop(storage);
+        assert_eq!(read(&world, e1), 10);
+
+        // overwrites
+        assert!(world.copy_component_dynamic(id_u32, e2, e1));
+        assert_eq!(read(&world, e1), 20);
+
+        assert!(!world.copy_component_dynamic(id_u32, e3, e1));
+        world.despawn(e3);
+        assert!(!world.copy_component_dynamic(id_u32, e1, e3));
+        assert!(world.copy_component_dynamic(id_u32, e1, e1));
+        assert_eq!(read(&world, e1), 20);
+    }
+
+    #[test]
+    fn reserve_then_insert_dynamic() {
+        let mut world = World::new();
+        let id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let write =
+            |value: u32| move |ptr: LtPtrWriteOnly<'_>| unsafe { *(ptr.1 as *mut u32) = value };
+
+        let e1 = world.spawn().id();
+        let reserved_1 = world.reserve_entity();
+        let reserved_2 = world.reserve_entity();
+        world
+            .insert_component_dynamic(reserved_2, id, write(12))
+            .unwrap_none();
+        assert!(world.is_alive(reserved_1) && world.is_alive(reserved_2));
+        let e2 = world.spawn().id();
+        world
+            .insert_component_dynamic(e1, id, write(10))
+            .unwrap_none();
+        let reserved_3 = world.reserve_entity();
+        world
+            .insert_component_dynamic(reserved_1, id, write(11))
+            .unwrap_none();
+        world
+            .insert_component_dynamic(reserved_3, id, write(13))
+            .unwrap_none();
+
+        let get = |world: &World, e| {
+            let (idx, storage) = world.get_component_dynamic(e, id).unwrap();
+            let value = unsafe { *(storage.get_element_ptr(idx).1 as *const u32) };
+            value
+        };
+        assert_eq!(get(&world, e1), 10);
+        assert_eq!(get(&world, reserved_1), 11);
+        assert_eq!(get(&world, reserved_2), 12);
+        assert_eq!(get(&world, reserved_3), 13);
+        assert_eq!(world.has_component_dynamic(e2, id), Some(false));
+        assert_eq!(
+            world.len_in_archetype(id, world.entities.meta(e1).unwrap().archetype),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn len_in_archetype() {
+        let mut world = World::new();
+        let id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let other_id = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let entities = [
+            world.spawn().id(),
+            world.spawn().id(),
+            world.spawn().insert(true).id(),
+        ];
+        for e in entities {
+            world
+                .insert_component_dynamic(e, id, |ptr| unsafe {
+                    *(ptr.1 as *mut u32) = 10;
+                })
+                .unwrap_none();
+        }
+
+        let archetype = world.entities.meta(entities[0]).unwrap().archetype;
+        let other_archetype = world.entities.meta(entities[2]).unwrap().archetype;
+        assert_eq!(world.len_in_archetype(id, archetype), Some(2));
+        assert_eq!(world.len_in_archetype(id, other_archetype), Some(1));
+        assert_eq!(world.len_in_archetype(id, 0), None);
+        assert_eq!(world.len_in_archetype(other_id, archetype), None);
+        assert_eq!(world.len_in_archetype(id, 100), None);
+
+        let q = world.query_dynamic(&[DynQueryParam::new_mut(id)]).unwrap();
+        assert_eq!(world.len_in_archetype(id, archetype), None);
+        drop(q);
+
+        world.despawn(entities[0]);
+        assert_eq!(world.len_in_archetype(id, archetype), Some(1));
+    }
+
+    #[test]
+    fn insert_bundle_dynamic() {
+        let mut world = World::new();
+        let id_u8 = world.new_dynamic_ecs_type_id(Layout::new::<u8>());
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let e1 = world.spawn().insert(true).id();
+
+        let archetypes_before = world.archetype_creation_count();
+        world.insert_bundle_dynamic(
+            e1,
+            &[
+                (id_u32, &10_u32.to_ne_bytes()),
+                (id_u8, &[12]),
+                (id_u64, &14_u64.to_ne_bytes()),
+            ],
+        );
+        assert_eq!(world.archetype_creation_count(), archetypes_before + 1);
+        assert_eq!(world.widest_archetype().component_count(), 4);
+        assert_eq!(*world.get_component::<bool>(e1).unwrap(), true);
+
+        // overwrites existing components and adds new ones in one go
+        let e2 = world.spawn().id();
+        world.insert_bundle_dynamic(e2, &[(id_u8, &[1])]);
+        world.insert_bundle_dynamic(e2, &[(id_u8, &[2]), (id_u64, &16_u64.to_ne_bytes())]);
+        world.insert_bundle_dynamic(e2, &[]);
+
+        assert_eq!(
+            world.take_all_dynamic(e1),
+            vec![
+                (id_u8, vec![12]),
+                (id_u32, 10_u32.to_ne_bytes().to_vec()),
+                (id_u64, 14_u64.to_ne_bytes().to_vec()),
+            ]
+        );
+        assert_eq!(
+            world.take_all_dynamic(e2),
+            vec![(id_u8, vec![2]), (id_u64, 16_u64.to_ne_bytes().to_vec())]
+        );
+
+        world.despawn(e2);
+        world.insert_bundle_dynamic(e2, &[(id_u8, &[1])]);
+        assert!(!world.is_alive(e2));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 4 bytes")]
+    fn insert_bundle_dynamic_wrong_size() {
+        let mut world = World::new();
+        let id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e = world.spawn().id();
+        world.insert_bundle_dynamic(e, &[(id, &[1, 2])]);
+    }
+
+    #[test]
+    #[should_panic(expected = "`insert_bundle_dynamic` called with unknown EcsTypeId(1)")]
+    fn insert_bundle_dynamic_unknown_id() {
+        let mut world = World::new();
+        let id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e = world.spawn().id();
+        world.insert_bundle_dynamic(e, &[(EcsTypeId(id.0 + 1), &[1, 2, 3, 4])]);
+    }
+
+    #[test]
+    fn clone_entity_filtered() {
+        let mut world = World::new();
+        let id_u8 = world.new_dynamic_ecs_type_id(Layout::new::<u8>());
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let src = world.spawn().insert(true).id();
+        world.insert_bundle_dynamic(
+            src,
+            &[
+                (id_u8, &[1]),
+                (id_u32, &10_u32.to_ne_bytes()),
+                (id_u64, &12_u64.to_ne_bytes()),
+            ],
+        );
+
+        let bool_id = world.type_to_ecs_type_id::<bool>().unwrap();
+        let clone = world.clone_entity_filtered(src, &[id_u64, id_u8, bool_id, id_u8]);
+        assert_eq!(world.has_component::<bool>(clone), Some(false));
+        assert_eq!(world.has_component_dynamic(clone, id_u32), Some(false));
+        assert_eq!(
+            world.take_all_dynamic(clone),
+            vec![(id_u8, vec![1]), (id_u64, 12_u64.to_ne_bytes().to_vec())]
+        );
+        assert_eq!(world.take_all_dynamic(src).len(), 3);
+
+        world.despawn(src);
+        let clone = world.clone_entity_filtered(src, &[id_u8]);
+        assert!(world.is_alive(clone));
+        assert_eq!(world.take_all_dynamic(clone), vec![]);
+    }
+
+    #[test]
+    fn despawn_returning() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u16 = world.new_dynamic_ecs_type_id(Layout::new::<u16>());
+        let e1 = world.spawn().insert(true).id();
+        let e2 = world.spawn().id();
+        for e in [e1, e2] {
+            world
+                .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                    *(ptr.1 as *mut u32) = 10;
+                })
+                .unwrap_none();
+        }
+        world
+            .insert_component_dynamic(e1, id_u16, |ptr| unsafe {
+                *(ptr.1 as *mut u16) = 12;
+            })
+            .unwrap_none();
+
+        assert_eq!(
+            world.despawn_returning(e1),
+            Some(vec![
+                (id_u32, 10_u32.to_ne_bytes().to_vec()),
+                (id_u16, 12_u16.to_ne_bytes().to_vec()),
+            ])
+        );
+        assert!(!world.is_alive(e1));
+        assert_eq!(world.despawn_returning(e1), None);
+        assert_eq!(world.has_component_dynamic(e2, id_u32), Some(true));
+        assert_eq!(world.query::<Entity>().unwrap().iter_mut().count(), 1);
+    }
+
+    #[test]
+    fn visit_components() {
+        let mut world = World::new();
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let id_u16 = world.new_dynamic_ecs_type_id(Layout::new::<u16>());
+        let e = world.spawn().insert(10_u32).id();
+        world.insert_bundle_dynamic(
+            e,
+            &[
+                (id_u16, &7_u16.to_ne_bytes()),
+                (id_u64, &12_u64.to_ne_bytes()),
+            ],
+        );
+
+        let mut visited = vec![];
+        world.visit_components(e, &mut |id, bytes| {
+            let bytes = bytes.iter().map(|b| unsafe { b.assume_init() });
+            visited.push((id, bytes.collect::<Vec<_>>()));
+        });
+        assert_eq!(
+            visited,
+            [
+                (id_u64, 12_u64.to_ne_bytes().to_vec()),
+                (id_u16, 7_u16.to_ne_bytes().to_vec()),
+            ]
+        );
+
+        let q = world
+            .query_dynamic(&[DynQueryParam::new_mut(id_u64)])
+            .unwrap();
+        let mut visited = vec![];
+        world.visit_components(e, &mut |id, _| visited.push(id));
+        assert_eq!(visited, [id_u16]);
+        drop(q);
+
+        world.despawn(e);
+        world.visit_components(e, &mut |_, _| unreachable!());
+    }
+
+    #[test]
+    fn take_all_dynamic() {
+        let mut world = World::new();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().insert(true).id();
+        for e in [e1, e2] {
+            world
+                .insert_component_dynamic(e, id_u64, |ptr| unsafe {
+                    *(ptr.1 as *mut u64) = 12;
+                })
+                .unwrap_none();
+            world
+                .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                    *(ptr.1 as *mut u32) = 10;
+                })
+                .unwrap_none();
+        }
+
+        let expected = vec![
+            (id_u32, 10_u32.to_ne_bytes().to_vec()),
+            (id_u64, 12_u64.to_ne_bytes().to_vec()),
+        ];
+        assert_eq!(world.take_all_dynamic(e1), expected);
+        assert_eq!(world.entities.meta(e1).unwrap().archetype, 0);
+        assert_eq!(world.take_all_dynamic(e1), vec![]);
+
+        assert_eq!(world.take_all_dynamic(e2), expected);
+        assert_eq!(world.has_component::<bool>(e2), Some(true));
+        assert_eq!(world.has_component_dynamic(e2, id_u32), Some(false));
+        world.despawn(e2);
+        assert_eq!(world.take_all_dynamic(e2), vec![]);
+    }
+
+    #[test]
+    fn take_component_dynamic() {
+        let mut world = World::new();
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let e1 = world.spawn().id();
+        let e2 = world.spawn().id();
+        for (e, value) in [(e1, 10_u64), (e2, 12_u64)] {
+            world
+                .insert_component_dynamic(e, id_u64, |ptr| unsafe {
+                    *(ptr.1 as *mut u64) = value;
+                })
+                .unwrap_none();
+            world
+                .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                    *(ptr.1 as *mut u32) = value as u32;
+                })
+                .unwrap_none();
+        }
+
+        let bytes = world.take_component_dynamic(e1, id_u64).unwrap();
+        assert_eq!(bytes, 10_u64.to_ne_bytes());
+        assert_eq!(world.has_component_dynamic(e1, id_u64), Some(false));
+        assert_eq!(world.has_component_dynamic(e1, id_u32), Some(true));
+        assert_eq!(world.take_component_dynamic(e1, id_u64), None);
+
+        let bytes = world.take_component_dynamic(e2, id_u64).unwrap();
+        assert_eq!(bytes, 12_u64.to_ne_bytes());
+        let bytes = world.take_component_dynamic(e2, id_u32).unwrap();
+        assert_eq!(bytes, 12_u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn basic_insert_dynamic() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+
+        world
+            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 10;
+            })
+            .unwrap_none();
+
+        let (idx, storage) = world.get_component_mut_dynamic(e, id_u32).unwrap();
+        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) }, 10);
+    }
+
+    #[test]
+    fn insert_overwrite_dynamic() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+
+        world
+            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 10;
+            })
+            .unwrap_none();
+
+        let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
+        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) }, 10);
+    }
+
+    #[test]
+    fn insert_archetype_change_dynamic() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+
+        world
+            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 10;
+            })
+            .unwrap_none();
+        world
+            .insert_component_dynamic(e, id_u64, |ptr| unsafe {
+                *(ptr.1 as *mut u64) = 12;
+            })
+            .unwrap_none();
+
+        assert_eq!(
+            unsafe {
+                *(world
+                    .insert_component_dynamic(e, id_u32, |ptr| {
+                        *(ptr.1 as *mut u32) = 15;
+                    })
+                    .unwrap()
+                    .1 as *const u32)
+            },
+            10
+        );
+
+        let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
+        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u32) }, 15);
+
+        let (idx, storage) = world.get_component_dynamic(e, id_u64).unwrap();
+        assert_eq!(unsafe { *(storage.get_element_ptr(idx).1 as *mut u64) }, 12);
+    }
+
+    #[test]
+    fn insert_on_dead_dynamic() {
+        let mut world = World::new();
+        let e = world.spawn().id();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        world
+            .insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = 10;
+            })
+            .unwrap_none();
+        world.despawn(e);
+        world
+            .insert_component_dynamic(e, id_u32, |_| unreachable!(""))
+            .unwrap_none();
+    }
+
+    #[test]
+    fn basic_remove_dynamic() {
         let mut world = World::new();
         let e = world.spawn().id();
         let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
@@ -941,4 +3482,147 @@ mod dynamic_tests {
         world.despawn(e);
         world.remove_component_dynamic(e, ecs_id).unwrap_none();
     }
+
+    #[test]
+    fn on_insert_hook_dynamic() {
+        use std::{cell::Cell, rc::Rc};
+
+        let mut world = World::new();
+        let e = world.spawn().id();
+        let id_u32 = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        let overwrites = Rc::new(Cell::new((0, 0)));
+        let counter = overwrites.clone();
+        world.on_insert(id_u32, move |_, overwrite| {
+            let (new, over) = counter.get();
+            match overwrite {
+                false => counter.set((new + 1, over)),
+                true => counter.set((new, over + 1)),
+            }
+        });
+
+        for n in 0..3 {
+            world.insert_component_dynamic(e, id_u32, |ptr| unsafe {
+                *(ptr.1 as *mut u32) = n;
+            });
+        }
+        assert_eq!(overwrites.get(), (1, 2));
+    }
+
+    #[test]
+    fn get_static_component_dynamic() {
+        let mut world = World::new();
+        let e = world.spawn().insert(10_u32).id();
+        let id_u32 = world.type_to_ecs_type_id::<u32>().unwrap();
+
+        let (idx, storage) = world.get_component_dynamic(e, id_u32).unwrap();
+        let ptr = storage.get_element_ptr(idx);
+        assert_eq!(ptr.1.len(), 4);
+        assert_eq!(unsafe { *(ptr.1 as *const u32) }, 10);
+    }
+
+    #[test]
+    fn query_dynamic_out_of_range_id() {
+        let mut world = World::new();
+        let u32_id = world.new_dynamic_ecs_type_id(Layout::new::<u32>());
+        world
+            .query_dynamic(&[DynQueryParam::new_ref(u32_id)])
+            .unwrap();
+        assert!(world
+            .query_dynamic(&[DynQueryParam::new_ref(EcsTypeId(u32_id.0 + 1))])
+            .is_err());
+    }
+
+    #[test]
+    fn sort_archetype() {
+        let mut world = World::new();
+        let id_u64 = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let entities = (0..10_u32)
+            .map(|n| {
+                let e = world.spawn().insert(n).id();
+                world.insert_component_dynamic(e, id_u64, |ptr| unsafe {
+                    *(ptr.1 as *mut u64) = n as u64 * 10;
+                });
+                e
+            })
+            .collect::<Vec<_>>();
+
+        let archetype = world.entities.meta(entities[0]).unwrap().archetype;
+        world.sort_archetype_by(archetype, |a, b| b.cmp(&a));
+
+        let mut sorted = entities.clone();
+        sorted.reverse();
+        assert_eq!(world.archetypes[archetype].entities, sorted);
+        for (n, &e) in entities.iter().enumerate() {
+            assert_eq!(*world.get_component::<u32>(e).unwrap(), n as u32);
+            let (idx, storage) = world.get_component_dynamic(e, id_u64).unwrap();
+            assert_eq!(
+                unsafe { *(storage.get_element_ptr(idx).1 as *const u64) },
+                n as u64 * 10
+            );
+        }
+
+        let mut q = world.query::<(Entity, &u32)>().unwrap();
+        let returned = q.iter_mut().map(|(e, n)| (e, *n)).collect::<Vec<_>>();
+        let expected = (0..10_u32)
+            .rev()
+            .map(|n| (entities[n as usize], n))
+            .collect::<Vec<_>>();
+        assert_eq!(returned, expected);
+    }
+
+    #[test]
+    fn interned_dynamic_ids() {
+        let mut world = World::new();
+        let pos1 = world.interned_dynamic_ecs_type_id(Layout::new::<u32>(), "position");
+        let pos2 = world.interned_dynamic_ecs_type_id(Layout::new::<u32>(), "position");
+        let vel = world.interned_dynamic_ecs_type_id(Layout::new::<u32>(), "velocity");
+        assert_eq!(pos1, pos2);
+        assert_ne!(pos1, vel);
+
+        let e = world.spawn().id();
+        world.insert_component_dynamic(e, pos1, |ptr| unsafe {
+            *(ptr.1 as *mut u32) = 10;
+        });
+        let (idx, storage) = world.get_component_dynamic(e, pos2).unwrap();
+        assert_eq!(
+            unsafe { *(storage.get_element_ptr(idx).1 as *const u32) },
+            10
+        );
+        drop(storage);
+        assert_eq!(world.has_component_dynamic(e, vel), Some(false));
+    }
+
+    #[should_panic(expected = "tag `position` was interned with")]
+    #[test]
+    fn interned_dynamic_ids_mismatched_layout() {
+        let mut world = World::new();
+        world.interned_dynamic_ecs_type_id(Layout::new::<u32>(), "position");
+        world.interned_dynamic_ecs_type_id(Layout::new::<u64>(), "position");
+    }
+
+    #[test]
+    fn component_layout() {
+        let mut world = World::new();
+        let id_static = world.type_to_ecs_type_id_or_create::<u64>();
+        let id_dynamic = world.new_dynamic_ecs_type_id(Layout::new::<[u16; 3]>());
+        assert_eq!(
+            world.component_layout(id_static),
+            Some(Layout::new::<u64>())
+        );
+        assert_eq!(
+            world.component_layout(id_dynamic),
+            Some(Layout::new::<[u16; 3]>())
+        );
+    }
+
+    #[test]
+    fn iter_component_ids() {
+        let mut world = World::new();
+        assert_eq!(world.iter_component_ids().count(), 0);
+        let id_u32 = world.type_to_ecs_type_id_or_create::<u32>();
+        let id_dyn = world.new_dynamic_ecs_type_id(Layout::new::<u64>());
+        let mut ids = world.iter_component_ids().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, [id_u32, id_dyn]);
+    }
 }